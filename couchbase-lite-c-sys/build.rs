@@ -13,23 +13,25 @@ fn bindgen_common() -> bindgen::Builder {
         .use_core()
         //.ctypes_prefix("libc")
         .whitelist_function("CBLDatabase_.*")
+        .whitelist_function("CBLEncryptionKey_.*")
+        .whitelist_function("CBLError_.*")
         .whitelist_function("CBLDocument_.*")
+        .whitelist_function("CBLListener_.*")
         .whitelist_function("CBL_Release")
+        .whitelist_function("CBL_Retain")
+        .whitelist_function("CBL_DatabaseExists")
+        .whitelist_function("CBL_CopyDatabase")
+        .whitelist_function("CBL_DeleteDatabase")
         .whitelist_function("CBLQuery_.*")
         .whitelist_function("CBLResultSet_.*")
-        .whitelist_function("FLValue_GetType")
-        .whitelist_function("FLValue_AsString")
-        .whitelist_function("FLValue_ToJSON")
-        .whitelist_function("FLDict_Get")
-        .whitelist_function("FLDict_IsEmpty")
-        .whitelist_function("FLMutableDict_Set")
-        .whitelist_function("FLDict_AsMutable")
-        .whitelist_function("FLSlot_SetString")
-        .whitelist_function("FLSlot_SetNull")
-        .whitelist_function("FLSlot_SetData")
-        .whitelist_function("FLSlot_SetValue")
-        .whitelist_function("FLSlot_SetBool")
-        .whitelist_function("FLSlot_SetInt")
+        .whitelist_function("FLValue_.*")
+        .whitelist_function("FLArray_.*")
+        .whitelist_function("FLDict_.*")
+        .whitelist_function("FLDictIterator_.*")
+        .whitelist_function("FLMutableArray_.*")
+        .whitelist_function("FLMutableDict_.*")
+        .whitelist_function("FLSlot_.*")
+        .whitelist_function("FLSliceResult_.*")
         .whitelist_function("FLStr")
         .whitelist_function("CBLEndpoint_NewWithURL")
         .whitelist_function("CBLEndpoint_Free")
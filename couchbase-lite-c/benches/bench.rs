@@ -4,10 +4,31 @@ extern crate criterion;
 use couchbase_lite_c::{Database, Document};
 use criterion::Criterion;
 use serde::{Deserialize, Serialize};
+use std::env;
 use std::fs;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Person {
+    pub first_name: String,
+    pub last_name: String,
+    pub age: u32,
+    pub tags: Vec<String>,
+}
+
+fn person() -> Person {
+    Person {
+        first_name: "James".to_string(),
+        last_name: "Bomb".to_string(),
+        age: 42,
+        tags: vec!["agent".to_string(), "007".to_string()],
+    }
+}
 
 fn test_dir() -> String {
-    let dir = "/tmp/benchdb".to_string();
+    // A fresh directory per run, so that runs don't see each other's documents
+    let uuid = Uuid::new_v4().to_string();
+    let dir = env::temp_dir().join("benchdb").join(uuid).to_string_lossy().into_owned();
     match fs::create_dir_all(dir.clone()) {
         Ok(_) => {}
         Err(e) => panic!("Cannot create database directory: {:?}", e),
@@ -20,33 +41,47 @@ fn open_database() -> Database {
     Database::open(test_dir(), &database_name).unwrap()
 }
 
-fn save_document_from_json() {
-    #[derive(Serialize, Deserialize, Debug)]
-    pub struct Person {
-        pub first_name: String,
-        pub last_name: String,
-    }
-    let person = Person {
-        first_name: "James".to_string(),
-        last_name: "Bomb".to_string(),
-    };
+fn save_document_from_json(database: &Database) {
+    let mut doc = Document::new(String::from("foo"));
+    doc.fill(serde_json::to_string(&person()).unwrap()).unwrap();
+    database.save_document(doc).unwrap();
+}
 
-    let database = open_database();
-    let doc_id = String::from("foo");
-    let doc = Document::new(doc_id);
-    doc.fill(serde_json::to_string_pretty(&person).unwrap()).unwrap();
-    assert_eq!("{\"first_name\":\"James\",\"last_name\":\"Bomb\"}", doc.jsonify());
+fn save_document_with_fleece(database: &Database) {
+    let mut doc = Document::new(String::from("foo"));
+    doc.set_properties(&person()).unwrap();
+    database.save_document(doc).unwrap();
+}
+
+fn read_document_from_json(database: &Database) -> Person {
+    let doc = database.get_document(String::from("foo")).unwrap();
+    serde_json::from_str(&doc.jsonify()).unwrap()
+}
 
-    let _saved = database.save_document(doc);
-    //assert_eq!(true, saved.is_ok());
-    //let saved = saved.unwrap();
-    //assert_eq!(doc_id, saved.id());
-    //assert_eq!(1, saved.sequence());
-    //assert_eq!("{\"first_name\":\"James\",\"last_name\":\"Bomb\"}", saved.jsonify());
+fn read_document_with_fleece(database: &Database) -> Person {
+    let doc = database.get_document(String::from("foo")).unwrap();
+    doc.properties().unwrap()
 }
 
 fn criterion_benchmark(c: &mut Criterion) {
-    c.bench_function("save document from json", |b| b.iter(save_document_from_json));
+    c.bench_function("save document from json", |b| {
+        let database = open_database();
+        b.iter(|| save_document_from_json(&database))
+    });
+    c.bench_function("save document with fleece serializer", |b| {
+        let database = open_database();
+        b.iter(|| save_document_with_fleece(&database))
+    });
+    c.bench_function("read document from json", |b| {
+        let database = open_database();
+        save_document_from_json(&database);
+        b.iter(|| read_document_from_json(&database))
+    });
+    c.bench_function("read document with fleece deserializer", |b| {
+        let database = open_database();
+        save_document_from_json(&database);
+        b.iter(|| read_document_with_fleece(&database))
+    });
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -1,7 +1,8 @@
 
 use ffi;
 
-use crate::to_ptr;
+use crate::errors::CouchbaseLiteError;
+use crate::to_cstring;
 
 /// Wrapper around `CBLAuthenticator`.
 ///
@@ -12,8 +13,12 @@ pub struct Authenticator {
 
 impl Authenticator {
     /// Create a new authenticator using the HTTP 'basic' authentication method.
-    pub fn new_basic(user: String, pwd: String) -> Self {
-        let authenticator = unsafe { ffi::CBLAuth_NewBasic(to_ptr(user), to_ptr(pwd)) };
-        Self { authenticator }
+    ///
+    /// Fails with `InvalidString` if the user name or password contains a NUL byte.
+    pub fn new_basic(user: String, pwd: String) -> Result<Self, CouchbaseLiteError> {
+        let user = to_cstring(&user)?;
+        let pwd = to_cstring(&pwd)?;
+        let authenticator = unsafe { ffi::CBLAuth_NewBasic(user.as_ptr(), pwd.as_ptr()) };
+        Ok(Self { authenticator })
     }
 }
\ No newline at end of file
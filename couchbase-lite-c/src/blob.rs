@@ -0,0 +1,66 @@
+use crate::document::Document;
+use crate::errors::{init_error, CouchbaseLiteError, NativeError};
+use crate::{slice_result_to_bytes, to_string};
+use ffi;
+
+/// A binary attachment of a document, wrapping `CBLBlob`.
+///
+/// The blob is owned by the document it was read from, and borrows it.
+pub struct Blob<'a> {
+    blob: *const ffi::CBLBlob,
+    document: &'a Document,
+}
+
+impl<'a> Blob<'a> {
+    /// Wraps the blob of a blob dictionary, returning `None` if `dict` isn't one.
+    pub(crate) fn from_dict(document: &'a Document, dict: ffi::FLDict) -> Option<Self> {
+        let blob = unsafe { ffi::FLDict_GetBlob(dict) };
+        if blob.is_null() {
+            None
+        } else {
+            Some(Blob { blob, document })
+        }
+    }
+
+    /// Returns the length of the content in bytes.
+    pub fn length(&self) -> u64 {
+        unsafe { ffi::CBLBlob_Length(self.blob) }
+    }
+
+    /// Returns the MIME type of the content, if it was given when the blob was created.
+    pub fn content_type(&self) -> Option<String> {
+        let content_type = unsafe { ffi::CBLBlob_ContentType(self.blob) };
+        if content_type.is_null() {
+            None
+        } else {
+            Some(to_string(content_type))
+        }
+    }
+
+    /// Returns the digest of the content, e.g. "sha1-...".
+    pub fn digest(&self) -> String {
+        to_string(unsafe { ffi::CBLBlob_Digest(self.blob) })
+    }
+
+    /// Reads the whole content from the document's database.
+    pub fn content(&self) -> Result<Vec<u8>, CouchbaseLiteError> {
+        let _open = self.document.check_open()?;
+        let mut error = init_error();
+        let content = unsafe { ffi::CBLBlob_LoadContent(self.blob, &mut error) };
+        if error.code == 0 {
+            Ok(slice_result_to_bytes(content))
+        } else {
+            Err(CouchbaseLiteError::CannotLoadBlobContent(NativeError::new(error)))
+        }
+    }
+}
+
+impl<'a> std::fmt::Debug for Blob<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Blob")
+            .field("digest", &self.digest())
+            .field("length", &self.length())
+            .field("content_type", &self.content_type())
+            .finish()
+    }
+}
@@ -0,0 +1,179 @@
+use crate::to_cstring;
+use ffi;
+
+use crate::errors::CouchbaseLiteError;
+use std::ffi::{CStr, CString};
+use std::path::{Path, PathBuf};
+
+/// An AES-256 key used to encrypt a database file, wrapping `CBLEncryptionKey`.
+///
+/// Encryption is only available with the Enterprise Edition of Couchbase Lite.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    bytes: [u8; 32],
+}
+
+impl EncryptionKey {
+    /// Uses 32 raw bytes as an AES-256 key.
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        EncryptionKey { bytes }
+    }
+
+    /// Derives an AES-256 key from a password, the same way the other Couchbase Lite platforms do.
+    pub fn from_password(password: &str) -> Result<Self, CouchbaseLiteError> {
+        let mut key = ffi::CBLEncryptionKey {
+            algorithm: 0,
+            bytes: [0; 32usize],
+        };
+        let password = to_cstring(password)?;
+        let derived = unsafe { ffi::CBLEncryptionKey_FromPassword(&mut key, password.as_ptr()) };
+        if derived {
+            Ok(EncryptionKey { bytes: key.bytes })
+        } else {
+            Err(CouchbaseLiteError::CannotDeriveEncryptionKey)
+        }
+    }
+
+    pub(crate) fn to_native(key: Option<&EncryptionKey>) -> ffi::CBLEncryptionKey {
+        match key {
+            // 1 -> kCBLEncryptionAES256
+            Some(key) => ffi::CBLEncryptionKey {
+                algorithm: 1,
+                bytes: key.bytes,
+            },
+            // 0 -> kCBLEncryptionNone
+            None => ffi::CBLEncryptionKey {
+                algorithm: 0,
+                bytes: [0; 32usize],
+            },
+        }
+    }
+}
+
+impl std::fmt::Debug for EncryptionKey {
+    // Never print the key material
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("EncryptionKey(AES-256)")
+    }
+}
+
+/// Options used to open a `Database`, wrapping `CBLDatabaseConfiguration`.
+///
+/// By default the database is created if missing, opened read-write and upgraded
+/// if it was written by an older version of Couchbase Lite.
+#[derive(Clone, Debug)]
+pub struct DatabaseConfig {
+    directory: PathBuf,
+    create: bool,
+    read_only: bool,
+    no_upgrade: bool,
+    encryption_key: Option<EncryptionKey>,
+}
+
+impl DatabaseConfig {
+    /// Creates a configuration for databases stored in `directory`.
+    pub fn new<P: AsRef<Path>>(directory: P) -> Self {
+        DatabaseConfig {
+            directory: directory.as_ref().to_path_buf(),
+            create: true,
+            read_only: false,
+            no_upgrade: false,
+            encryption_key: None,
+        }
+    }
+
+    /// Creates the database file if it doesn't exist (enabled by default).
+    pub fn create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+
+    /// Opens the database file read-only.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Refuses to upgrade a database written by an older version of Couchbase Lite.
+    pub fn no_upgrade(mut self, no_upgrade: bool) -> Self {
+        self.no_upgrade = no_upgrade;
+        self
+    }
+
+    /// Encrypts the database file with the given key.
+    pub fn encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Returns the directory the database lives in.
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Returns true if the database is opened with an encryption key.
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption_key.is_some()
+    }
+
+    fn flags(&self) -> ffi::CBLDatabaseFlags {
+        // 1 -> kCBLDatabase_Create, 2 -> kCBLDatabase_ReadOnly, 4 -> kCBLDatabase_NoUpgrade
+        let mut flags: ffi::CBLDatabaseFlags = 0;
+        if self.create {
+            flags |= 1;
+        }
+        if self.read_only {
+            flags |= 2;
+        }
+        if self.no_upgrade {
+            flags |= 4;
+        }
+        flags
+    }
+
+    /// Returns the directory as a native string, to be kept alive while `to_native` is used.
+    pub(crate) fn native_directory(&self) -> Result<CString, CouchbaseLiteError> {
+        to_cstring(&self.directory.to_string_lossy())
+    }
+
+    /// Returns the native configuration, borrowing `directory` from `native_directory`.
+    pub(crate) fn to_native(&self, directory: &CStr) -> ffi::CBLDatabaseConfiguration {
+        ffi::CBLDatabaseConfiguration {
+            directory: directory.as_ptr(),
+            flags: self.flags(),
+            encryptionKey: EncryptionKey::to_native(self.encryption_key.as_ref()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::DatabaseConfig;
+    use crate::EncryptionKey;
+
+    #[test]
+    fn default_flags() {
+        let config = DatabaseConfig::new("/tmp/testdb");
+        assert_eq!(1, config.flags());
+        assert_eq!("/tmp/testdb", config.directory().to_str().unwrap());
+    }
+
+    #[test]
+    fn combined_flags() {
+        let config = DatabaseConfig::new("/tmp/testdb").create(false).read_only(true).no_upgrade(true);
+        assert_eq!(6, config.flags());
+    }
+
+    #[test]
+    fn encryption_key() {
+        let config = DatabaseConfig::new("/tmp/testdb");
+        let directory = config.native_directory().unwrap();
+        assert_eq!(0, config.to_native(&directory).encryptionKey.algorithm);
+
+        let config = config.encryption_key(EncryptionKey::from_bytes([7; 32]));
+        let native = config.to_native(&directory);
+        assert_eq!(1, native.encryptionKey.algorithm);
+        assert_eq!([7; 32], native.encryptionKey.bytes);
+        assert_eq!("EncryptionKey(AES-256)", format!("{:?}", EncryptionKey::from_bytes([7; 32])));
+    }
+}
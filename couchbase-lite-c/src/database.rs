@@ -1,80 +1,313 @@
-use crate::to_ptr;
+use crate::to_cstring;
 use crate::to_string;
 use ffi;
 
+use crate::config::DatabaseConfig;
+use crate::config::EncryptionKey;
 use crate::document::Document;
 use crate::errors::init_error;
+use crate::errors::open_error;
 use crate::errors::CouchbaseLiteError;
+use crate::errors::NativeError;
+use crate::listener::{
+    database_change_listener, document_change_listener, notifications_ready, DatabaseChangeCallback, DocumentChangeCallback, ListenerContext, ListenerToken,
+    NotificationsReadyCallback,
+};
+use crate::maintenance::{database_size, MaintenanceReport, MaintenanceType};
 use crate::query::Query;
+use crate::rollback::Snapshot;
+use crate::typed::TypedDocument;
 
 use core::ptr;
-use std::cell::Cell;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::any::Any;
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw::c_void;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+/// Conflict-handling strategy when saving or deleting a document, wrapping `CBLConcurrencyControl`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Concurrency {
+    /// The current save/delete overwrites any conflicting revision.
+    LastWriteWins,
+    /// The current save/delete fails if there is a conflicting revision.
+    FailOnConflict,
+}
+
+impl Concurrency {
+    fn to_native(self) -> ffi::CBLConcurrencyControl {
+        match self {
+            Concurrency::LastWriteWins => 0,
+            Concurrency::FailOnConflict => 1,
+        }
+    }
+}
+
+/// What `Database::update_document` does with the document once the update closure returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateAction {
+    /// Save the modified document.
+    Save,
+    /// Leave the document unchanged.
+    Cancel,
+}
+
+/// Options of `Database::update_document_with`.
 #[derive(Clone, Debug)]
+pub struct UpdateOptions {
+    /// How many times the update is retried after a conflict before giving up.
+    pub max_retries: u32,
+    /// Creates an empty document when there is none with the given ID.
+    pub create_if_missing: bool,
+}
+
+impl Default for UpdateOptions {
+    fn default() -> Self {
+        UpdateOptions {
+            max_retries: 10,
+            create_if_missing: false,
+        }
+    }
+}
+
+/// Handle on an open database, wrapping `CBLDatabase`.
+///
+/// Handles are reference-counted: cloning one retains the underlying `CBLDatabase` and dropping
+/// it releases it. Closing a handle closes the database for all its clones; otherwise the database
+/// is closed when the last clone is dropped.
+///
+/// `CBLDatabase` is thread-safe (the C library serializes the calls made on a database), so
+/// handles are `Send` and `Sync` and can be shared between threads. A batch or transaction belongs
+/// to the thread that started it: another thread starting one, or writing outside of a batch,
+/// waits until it ends, so that its changes never become part of the running batch.
+///
+/// Calls are re-entrant: listeners and batch units may use the database, even while another
+/// thread waits to close it.
 pub struct Database {
     pub db: *mut ffi::CBLDatabase,
-    open: Cell<bool>,   // this could just be a bool but then we'd have to
-                        //incompatibly change close signature to fn close(&mut self)
+    shared: Arc<SharedState>,
+}
+
+unsafe impl Send for Database {}
+unsafe impl Sync for Database {}
+
+/// State shared by all the clones of a `Database`, which holds its own reference on the `CBLDatabase`.
+pub(crate) struct SharedState {
+    db: *mut ffi::CBLDatabase,
+    open: Mutex<OpenState>,
+    // Notified when an `OpenGuard` is dropped or the database is closed
+    released: Condvar,
+    // Cached when opening, as they remain meaningful once the database is closed
+    name: String,
+    path: String,
+    // Contexts registered with `buffer_notifications`, kept alive as long as the database since
+    // the C library may still be running a replaced one
+    notifications_ready: Mutex<Vec<Box<dyn Any + Send>>>,
+    batch: Mutex<BatchState>,
+    // Notified when a batch or a write outside of a batch ends
+    batch_ended: Condvar,
+}
+
+unsafe impl Send for SharedState {}
+unsafe impl Sync for SharedState {}
+
+/// Whether a database is open, and which threads are using it.
+struct OpenState {
+    open: bool,
+    // The thread of each live `OpenGuard`
+    users: Vec<ThreadId>,
+    // The number of threads waiting to close or delete the database
+    closing: usize,
+}
+
+/// State of the batches and transactions running on a database.
+#[derive(Default)]
+struct BatchState {
+    owner: Option<ThreadId>,
+    // The length of `snapshots` when each of the nested batches began, the outermost one first
+    savepoints: Vec<usize>,
+    // The documents written in the batch, as they were before each write
+    snapshots: Vec<Snapshot>,
+    // Threads writing outside of a batch, which must be done before another thread starts one
+    writers: Vec<ThreadId>,
+}
+
+/// Keeps the database open while held: `close` and `delete` wait until the guards of the other
+/// threads are dropped.
+///
+/// A thread holding a guard can always take more, e.g. in a listener called by the C library during
+/// a write, even while another thread waits to close the database.
+pub(crate) struct OpenGuard<'a> {
+    shared: &'a SharedState,
+}
+
+impl<'a> Drop for OpenGuard<'a> {
+    fn drop(&mut self) {
+        let current = thread::current().id();
+        let mut state = self.shared.open.lock().unwrap();
+        if let Some(position) = state.users.iter().position(|user| *user == current) {
+            state.users.swap_remove(position);
+        }
+        self.shared.released.notify_all();
+    }
+}
+
+impl Drop for SharedState {
+    fn drop(&mut self) {
+        if self.open.get_mut().unwrap().open {
+            let mut error = init_error();
+            unsafe { ffi::CBLDatabase_Close(self.db, &mut error) };
+        }
+        unsafe { ffi::CBL_Release(self.db as *mut ffi::CBLRefCounted) };
+    }
 }
 
 impl Database {
+    /// Takes ownership of a reference on `db`.
     fn from(db: *mut ffi::CBLDatabase) -> Self {
-        Database{ db, open: Cell::new(true) }
+        let shared = SharedState {
+            db,
+            open: Mutex::new(OpenState {
+                open: true,
+                users: Vec::new(),
+                closing: 0,
+            }),
+            released: Condvar::new(),
+            name: to_string(unsafe { ffi::CBLDatabase_Name(db) }),
+            path: to_string(unsafe { ffi::CBLDatabase_Path(db) }),
+            notifications_ready: Mutex::new(Vec::new()),
+            batch: Mutex::new(BatchState::default()),
+            batch_ended: Condvar::new(),
+        };
+        Database {
+            db: retain(db),
+            shared: Arc::new(shared),
+        }
+    }
+
+    /// Returns a handle on the database a listener was registered on, sharing its open and batch
+    /// state, or `None` if all its handles were dropped in the meantime.
+    pub(crate) fn from_listener(shared: &Weak<SharedState>) -> Option<Self> {
+        shared.upgrade().map(|shared| Database {
+            db: retain(shared.db),
+            shared,
+        })
+    }
+
+    fn listener_context<C>(&self, callback: C) -> Box<ListenerContext<C>> {
+        Box::new(ListenerContext {
+            database: Arc::downgrade(&self.shared),
+            callback,
+        })
     }
 
     pub fn open(directory: String, name: &str) -> Result<Self, CouchbaseLiteError> {
+        Database::open_with(name, &DatabaseConfig::new(directory))
+    }
+
+    /// Opens a database, or creates it if it doesn't exist yet and the configuration allows it.
+    ///
+    /// Returns `DatabaseNotFound` when the database doesn't exist and creation is disabled,
+    /// `DatabaseLocked` when another process holds it and `DatabaseCorrupt` when the file is unreadable.
+    pub fn open_with(name: &str, config: &DatabaseConfig) -> Result<Self, CouchbaseLiteError> {
         let mut error = init_error();
-        let database_name = to_ptr(name.to_string());
-        let flags: ffi::CBLDatabaseFlags = 1;
-        // No encryption (default)
-        let encrypt_galgo: ffi::CBLEncryptionAlgorithm = 0;
-        let config = ffi::CBLDatabaseConfiguration {
-            directory: to_ptr(directory),
-            flags,
-            encryptionKey: ffi::CBLEncryptionKey {
-                algorithm: encrypt_galgo,
-                bytes: [0; 32usize],
-            },
-        };
-        let db = unsafe { ffi::CBLDatabase_Open(database_name, &config, &mut error) };
-        if error.code == 0 {
-            println!("open database status: {:?} (OK)", error);
+        let database_name = to_cstring(name)?;
+        let directory = config.native_directory()?;
+        let native_config = config.to_native(&directory);
+        let db = unsafe { ffi::CBLDatabase_Open(database_name.as_ptr(), &native_config, &mut error) };
+        if error.code == 0 && !db.is_null() {
             Ok(Database::from(db))
         } else {
-            println!("open database error: {:?}", error);
-            Err(CouchbaseLiteError::CannotOpenDatabase(error))
+            Err(open_error(error, config))
+        }
+    }
+
+    /// Returns true if a database with the given name exists in the given directory.
+    pub fn exists<P: AsRef<Path>>(name: &str, directory: P) -> bool {
+        // No database name or directory contains a NUL byte
+        match (to_cstring(name), to_cstring(&directory.as_ref().to_string_lossy())) {
+            (Ok(database_name), Ok(directory)) => unsafe { ffi::CBL_DatabaseExists(database_name.as_ptr(), directory.as_ptr()) },
+            _ => false,
+        }
+    }
+
+    /// Copies a database file to a new name in the configuration's directory, e.g. to install a
+    /// pre-populated database shipped with the application. Don't copy the file directly, as the
+    /// database's UUIDs must be regenerated.
+    pub fn copy<P: AsRef<Path>>(from_path: P, name: &str, config: &DatabaseConfig) -> Result<(), CouchbaseLiteError> {
+        let mut error = init_error();
+        let from_path = to_cstring(&from_path.as_ref().to_string_lossy())?;
+        let database_name = to_cstring(name)?;
+        let directory = config.native_directory()?;
+        let config = config.to_native(&directory);
+        let status = unsafe { ffi::CBL_CopyDatabase(from_path.as_ptr(), database_name.as_ptr(), &config, &mut error) };
+        if error.code == 0 && status {
+            Ok(())
+        } else {
+            Err(CouchbaseLiteError::CannotCopyDatabase(NativeError::new(error)))
+        }
+    }
+
+    /// Deletes a database file that isn't open. Returns false if there was no such database.
+    pub fn delete_named<P: AsRef<Path>>(name: &str, directory: P) -> Result<bool, CouchbaseLiteError> {
+        let mut error = init_error();
+        let database_name = to_cstring(name)?;
+        let directory = to_cstring(&directory.as_ref().to_string_lossy())?;
+        let deleted = unsafe { ffi::CBL_DeleteDatabase(database_name.as_ptr(), directory.as_ptr(), &mut error) };
+        if error.code == 0 {
+            Ok(deleted)
+        } else {
+            Err(CouchbaseLiteError::CannotDeleteDatabase(NativeError::new(error)))
         }
     }
 
     pub fn create_index(&self, name: &str, column_expression: &str) -> Result<(), CouchbaseLiteError> {
         let mut error = init_error();
-        let index_name = to_ptr(name.to_string());
+        let index_name = to_cstring(name)?;
+        let key_expressions = to_cstring(column_expression)?;
 
         let config = ffi::CBLIndexSpec {
             type_: 0,//0 -> kCBLValueIndex -> An index that stores property or expression values, 1 -> kCBLFullTextIndex -> An index of strings, that enables searching for words with `MATCH`
-            keyExpressionsJSON: to_ptr(column_expression.to_string()),
+            keyExpressionsJSON: key_expressions.as_ptr(),
             ignoreAccents: false,
             language: ptr::null()
         };
-        let result = unsafe { ffi::CBLDatabase_CreateIndex(self.db, index_name, config, &mut error) };
+        let result = self.write(None, || unsafe { ffi::CBLDatabase_CreateIndex(self.db, index_name.as_ptr(), config, &mut error) })?;
         if result {
             Ok(())
         } else {
-            Err(CouchbaseLiteError::CannotOpenDatabase(error))
+            Err(CouchbaseLiteError::CannotCreateIndex(NativeError::new(error)))
         }
     }
 
     /// Creates a new, empty document in memory. It will not be added to a database until saved.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` contains a NUL byte, see `try_create_document`.
     pub fn create_document(&self, id: String) -> Document {
-        let doc_id = to_ptr(id);
-        let doc = unsafe { ffi::CBLDocument_New(doc_id) };
-        Document::from_raw(self.db, doc)
+        self.try_create_document(&id).expect("document IDs can't contain NUL bytes")
+    }
+
+    /// Same as `create_document`, failing with `InvalidString` if `id` contains a NUL byte.
+    pub fn try_create_document(&self, id: &str) -> Result<Document, CouchbaseLiteError> {
+        let doc_id = to_cstring(id)?;
+        let doc = unsafe { ffi::CBLDocument_New(doc_id.as_ptr()) };
+        Ok(Document::from_raw(self, doc))
     }
 
     /// Fetches a document with given id (if there is one).
     pub fn get_document(&self, id: String) -> Option<Document> {
-        let doc_id = to_ptr(id);
-        let doc = unsafe { ffi::CBLDatabase_GetMutableDocument(self.db, doc_id) };
+        let _open = self.check_open().ok()?;
+        // No document ID contains a NUL byte
+        let doc_id = to_cstring(&id).ok()?;
+        let doc = unsafe { ffi::CBLDatabase_GetMutableDocument(self.db, doc_id.as_ptr()) };
         if doc.is_null() {
             None
         } else {
@@ -86,13 +319,44 @@ impl Database {
                 // document is deleted
                 None
             } else {
-                Some(Document::from_raw(self.db, doc))
+                Some(Document::from_raw(self, doc))
             }
         }
     }
 
-    /// Saves a (mutable) document to the database.
+    /// Saves a (mutable) document to the database, overwriting any conflicting revision.
     pub fn save_document(&self, document: Document) -> Result<Document, CouchbaseLiteError> {
+        self.save_document_with(document, Concurrency::LastWriteWins)
+    }
+
+    /// Saves a (mutable) document to the database with the given conflict-handling strategy.
+    ///
+    /// With `Concurrency::FailOnConflict`, returns `Conflict` if the document was changed in the
+    /// database since it was fetched; the caller can then fetch it again and retry.
+    pub fn save_document_with(&self, document: Document, concurrency: Concurrency) -> Result<Document, CouchbaseLiteError> {
+        self.check_savable(&document)?;
+        let mut error = init_error();
+        let saved: *const ffi::CBLDocument =
+            self.write(Some(document.native_id()), || unsafe { ffi::CBLDatabase_SaveDocument(self.db, document.doc, concurrency.to_native(), &mut error) })?;
+        if error.code == 0 && saved != ptr::null() {
+            let doc = unsafe {
+                let doc = ffi::CBLDocument_MutableCopy(saved);
+                ffi::CBL_Release(saved as *mut ffi::CBLRefCounted);
+                doc
+            };
+            Ok(Document::from_raw(self, doc))
+        } else if is_conflict(&error) {
+            Err(CouchbaseLiteError::Conflict { doc_id: document.id() })
+        } else {
+            Err(CouchbaseLiteError::CannotSaveDocument(NativeError::for_document(error, document.id())))
+        }
+    }
+
+    /// Preconditions shared by all the ways of saving a document.
+    fn check_savable(&self, document: &Document) -> Result<(), CouchbaseLiteError> {
+        // Only fails early: `write` keeps the database open while saving
+        drop(self.check_open()?);
+        drop(document.check_open()?);
         let is_empty_doc = unsafe {
             let dict = ffi::CBLDocument_MutableProperties(document.doc);
             ffi::FLDict_IsEmpty(dict)
@@ -100,150 +364,736 @@ impl Database {
         if is_empty_doc {
             Err(CouchbaseLiteError::CannotSaveEmptyDocument)
         } else {
-            let mut error = init_error();
-            let concurrency_last_write_wins: ffi::CBLConcurrencyControl = 0; // concurrency_fail_on_conflict: ffi::CBLConcurrencyControl = 1
-            let _json: *mut ::std::os::raw::c_char = unsafe { ffi::CBLDocument_PropertiesAsJSON(document.doc) };
-
-            //println!("BEFORE save document doc: {:?}", to_string(json));
-            let saved: *const ffi::CBLDocument =
-                unsafe { ffi::CBLDatabase_SaveDocument(self.db, document.doc, concurrency_last_write_wins, &mut error) };
-            //println!("save document error: {:?}", error);
-            if error.code == 0 && saved != ptr::null() {
-                let _json: *mut ::std::os::raw::c_char = unsafe { ffi::CBLDocument_PropertiesAsJSON(saved) };
-                //println!("AFTER save document saved_doc: {:?}", to_string(json));
-                let doc = unsafe { ffi::CBLDocument_MutableCopy(saved) };
-                Ok(Document::from_raw(self.db, doc))
-            } else {
-                Err(CouchbaseLiteError::CannotSaveDocument(error))
-            }
+            Ok(())
         }
     }
 
-    /// Deletes a document from the database. Deletions are replicated.
+    /// Saves a (mutable) document to the database, calling `conflict_handler` if the document was
+    /// changed in the database since it was fetched.
     ///
-    /// Warning You are still responsible for releasing the CBLDocument.
-    ///
-    /// ### Arguments
+    /// The handler receives the document being saved, which it may modify (e.g. to merge both
+    /// versions), and the revision currently in the database (`None` if it was deleted), which it can
+    /// only read. It returns true to save the document, or false to give up, in which case `Conflict`
+    /// is returned.
+    /// A panic in the handler aborts the save and is propagated once back from the C library.
+    pub fn save_document_with_conflict_handler<F>(&self, document: Document, mut conflict_handler: F) -> Result<Document, CouchbaseLiteError>
+    where
+        F: FnMut(&mut Document, Option<&Document>) -> bool,
+    {
+        self.check_savable(&document)?;
+        let mut context = ConflictContext {
+            handler: &mut conflict_handler,
+            panic: None,
+        };
+        let mut error = init_error();
+        let saved = self.write(Some(document.native_id()), || unsafe {
+            ffi::CBLDatabase_SaveDocumentResolving(
+                self.db,
+                document.doc,
+                Some(save_conflict_handler),
+                &mut context as *mut ConflictContext as *mut c_void,
+                &mut error,
+            )
+        })?;
+        if let Some(cause) = context.panic.take() {
+            panic::resume_unwind(cause);
+        }
+        if error.code == 0 && saved != ptr::null() {
+            let doc = unsafe {
+                let doc = ffi::CBLDocument_MutableCopy(saved);
+                ffi::CBL_Release(saved as *mut ffi::CBLRefCounted);
+                doc
+            };
+            Ok(Document::from_raw(self, doc))
+        } else if is_conflict(&error) {
+            Err(CouchbaseLiteError::Conflict { doc_id: document.id() })
+        } else {
+            Err(CouchbaseLiteError::CannotSaveDocument(NativeError::for_document(error, document.id())))
+        }
+    }
+
+    /// Fetches a document, lets `update` modify it and saves it, starting over from a fresh copy of
+    /// the document whenever the save conflicts with a concurrent change. Uses the default `UpdateOptions`.
     ///
-    /// * document The document to delete.
-    /// * concurrency Conflict-handling strategy.
-    /// * error On failure, the error will be written here.
+    /// Returns the saved document, or `None` if `update` returned `UpdateAction::Cancel`.
+    pub fn update_document<F>(&self, id: &str, update: F) -> Result<Option<Document>, CouchbaseLiteError>
+    where
+        F: FnMut(&mut Document) -> Result<UpdateAction, CouchbaseLiteError>,
+    {
+        self.update_document_with(id, &UpdateOptions::default(), update)
+    }
+
+    /// Same as `update_document`, with a configurable retry limit and optional creation of a missing
+    /// document. Returns `DocumentNotFound` if the document doesn't exist and creation is disabled, and
+    /// `Conflict` once the retries are exhausted. `update` may be called several times.
+    pub fn update_document_with<F>(&self, id: &str, options: &UpdateOptions, mut update: F) -> Result<Option<Document>, CouchbaseLiteError>
+    where
+        F: FnMut(&mut Document) -> Result<UpdateAction, CouchbaseLiteError>,
+    {
+        let mut retries = 0;
+        loop {
+            let mut document = match self.get_document(id.to_string()) {
+                Some(document) => document,
+                None if options.create_if_missing => self.try_create_document(id)?,
+                None => return Err(CouchbaseLiteError::DocumentNotFound(id.to_string())),
+            };
+            if update(&mut document)? == UpdateAction::Cancel {
+                return Ok(None);
+            }
+            match self.save_document_with(document, Concurrency::FailOnConflict) {
+                Err(CouchbaseLiteError::Conflict { .. }) if retries < options.max_retries => retries += 1,
+                result => return result.map(Some),
+            }
+        }
+    }
+
+    /// Fetches a document and deserializes its properties, or returns `None` if there is no such document.
+    pub fn get<T: DeserializeOwned>(&self, id: &str) -> Result<Option<TypedDocument<T>>, CouchbaseLiteError> {
+        drop(self.check_open()?);
+        match self.get_document(id.to_string()) {
+            Some(document) => {
+                let value = document.properties()?;
+                Ok(Some(TypedDocument::new(&document, value)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Serializes `value` as the properties of a document and saves it, creating the document if
+    /// needed and overwriting any conflicting revision. `value` must serialize to a non-empty map.
+    pub fn put<'a, T: Serialize>(&self, id: &str, value: &'a T) -> Result<TypedDocument<&'a T>, CouchbaseLiteError> {
+        let mut document = match self.get_document(id.to_string()) {
+            Some(document) => document,
+            None => self.try_create_document(id)?,
+        };
+        document.set_properties(value)?;
+        let saved = self.save_document(document)?;
+        Ok(TypedDocument::new(&saved, value))
+    }
+
+    /// Deletes a document from the database, overwriting any conflicting revision. Deletions are replicated.
     ///
     /// ### Return value
     ///
     /// True if the document was deleted, false if an error occurred.
     pub fn delete_document(&self, document: Document) -> Result<bool, CouchbaseLiteError> {
+        self.delete_document_with(document, Concurrency::LastWriteWins)
+    }
+
+    /// Deletes a document from the database with the given conflict-handling strategy. Deletions are replicated.
+    ///
+    /// With `Concurrency::FailOnConflict`, returns `Conflict` if the document was changed in the
+    /// database since it was fetched.
+    pub fn delete_document_with(&self, document: Document, concurrency: Concurrency) -> Result<bool, CouchbaseLiteError> {
+        drop(self.check_open()?);
+        drop(document.check_open()?);
         let mut error = init_error();
-        let concurrency_last_write_wins: ffi::CBLConcurrencyControl = 0;
-        let deleted = unsafe { ffi::CBLDocument_Delete(document.doc, concurrency_last_write_wins, &mut error) };
+        let deleted = self.write(Some(document.native_id()), || unsafe { ffi::CBLDocument_Delete(document.doc, concurrency.to_native(), &mut error) })?;
         if error.code == 0 {
             Ok(deleted)
+        } else if is_conflict(&error) {
+            Err(CouchbaseLiteError::Conflict { doc_id: document.id() })
+        } else {
+            Err(CouchbaseLiteError::CannotDeleteDocument(NativeError::for_document(error, document.id())))
+        }
+    }
+
+    /// Deletes a document given its ID, without making a mutable copy of it first. Deletions are replicated.
+    /// Returns `DocumentNotFound` if there is no such document.
+    pub fn delete_document_by_id(&self, id: &str, concurrency: Concurrency) -> Result<(), CouchbaseLiteError> {
+        let doc_id = to_cstring(id)?;
+        let doc = {
+            let _open = self.check_open()?;
+            unsafe { ffi::CBLDatabase_GetDocument(self.db, doc_id.as_ptr()) }
+        };
+        if doc.is_null() {
+            return Err(CouchbaseLiteError::DocumentNotFound(id.to_string()));
+        }
+        // Same as get_document: an empty document is a tombstone
+        if unsafe { ffi::FLDict_IsEmpty(ffi::CBLDocument_Properties(doc)) } {
+            unsafe { ffi::CBL_Release(doc as *mut ffi::CBLRefCounted) };
+            return Err(CouchbaseLiteError::DocumentNotFound(id.to_string()));
+        }
+        let mut error = init_error();
+        let deleted = self.write(Some(doc_id.as_c_str()), || unsafe { ffi::CBLDocument_Delete(doc, concurrency.to_native(), &mut error) });
+        unsafe { ffi::CBL_Release(doc as *mut ffi::CBLRefCounted) };
+        let deleted = deleted?;
+        if error.code == 0 && deleted {
+            Ok(())
+        } else if is_conflict(&error) {
+            Err(CouchbaseLiteError::Conflict { doc_id: id.to_string() })
+        } else {
+            Err(CouchbaseLiteError::CannotDeleteDocument(NativeError::for_document(error, id)))
+        }
+    }
+
+    /// Deletes many documents given their IDs inside a single batch, returning the outcome for each ID.
+    /// The `Err` case only reports a failure of the batch itself.
+    pub fn delete_documents<S: AsRef<str>>(
+        &self,
+        ids: &[S],
+        concurrency: Concurrency,
+    ) -> Result<Vec<(String, Result<(), CouchbaseLiteError>)>, CouchbaseLiteError> {
+        self.in_batch_with_result(&|| {
+            Ok(ids
+                .iter()
+                .map(|id| {
+                    let id = id.as_ref();
+                    (id.to_string(), self.delete_document_by_id(id, concurrency))
+                })
+                .collect())
+        })
+    }
+
+    /// Purges a document: removes all traces of it from the local database. Unlike deletion,
+    /// purges are not replicated. Returns `DocumentNotFound` if the document isn't in the database,
+    /// e.g. because it was never saved, and `DocumentFromOtherDatabase` if it was fetched from or
+    /// saved to another database.
+    pub fn purge_document(&self, document: &Document) -> Result<(), CouchbaseLiteError> {
+        drop(self.check_open()?);
+        if !document.database().map_or(false, |database| self.is_same(database)) {
+            return Err(CouchbaseLiteError::DocumentFromOtherDatabase { doc_id: document.id() });
+        }
+        drop(document.check_open()?);
+        if document.sequence() == 0 {
+            return Err(CouchbaseLiteError::DocumentNotFound(document.id()));
+        }
+        let mut error = init_error();
+        let status = self.write(Some(document.native_id()), || unsafe { ffi::CBLDocument_Purge(document.doc, &mut error) })?;
+        purge_result(status, error, || document.id())
+    }
+
+    /// Purges a document given its ID. Returns `DocumentNotFound` if there is no such document.
+    pub fn purge_document_by_id(&self, id: &str) -> Result<(), CouchbaseLiteError> {
+        let mut error = init_error();
+        let doc_id = to_cstring(id)?;
+        let status = self.write(Some(doc_id.as_c_str()), || unsafe { ffi::CBLDatabase_PurgeDocumentByID(self.db, doc_id.as_ptr(), &mut error) })?;
+        purge_result(status, error, || id.to_string())
+    }
+
+    /// Sets or clears (with `None`) the time at which a document expires. Once expired, the document
+    /// is purged from the database: it disappears locally, but the purge is not replicated.
+    pub fn set_document_expiration(&self, id: &str, expiration: Option<SystemTime>) -> Result<(), CouchbaseLiteError> {
+        let mut error = init_error();
+        let doc_id = to_cstring(id)?;
+        // CBLTimestamp is a number of milliseconds since the Unix epoch, 0 meaning no expiration
+        let timestamp: ffi::CBLTimestamp = match expiration {
+            Some(time) => match time.duration_since(UNIX_EPOCH) {
+                Ok(elapsed) => (elapsed.as_millis() as i64).max(1),
+                // Before the epoch: already expired
+                Err(_) => 1,
+            },
+            None => 0,
+        };
+        let status = self.write(Some(doc_id.as_c_str()), || unsafe { ffi::CBLDatabase_SetDocumentExpiration(self.db, doc_id.as_ptr(), timestamp, &mut error) })?;
+        if error.code == 0 && status {
+            Ok(())
+        } else {
+            Err(CouchbaseLiteError::CannotSetDocumentExpiration(NativeError::for_document(error, id)))
+        }
+    }
+
+    /// Returns the time at which a document expires, or `None` if it has no expiration.
+    pub fn document_expiration(&self, id: &str) -> Result<Option<SystemTime>, CouchbaseLiteError> {
+        let _open = self.check_open()?;
+        let mut error = init_error();
+        let doc_id = to_cstring(id)?;
+        let timestamp = unsafe { ffi::CBLDatabase_GetDocumentExpiration(self.db, doc_id.as_ptr(), &mut error) };
+        if error.code != 0 {
+            Err(CouchbaseLiteError::CannotGetDocumentExpiration(NativeError::for_document(error, id)))
+        } else if timestamp <= 0 {
+            Ok(None)
         } else {
-            Err(CouchbaseLiteError::CannotDeleteDocument(error))
+            Ok(Some(UNIX_EPOCH + Duration::from_millis(timestamp as u64)))
         }
     }
 
     /// Creates a new query by compiling the input string.
     pub fn new_query(&self, n1ql_query: String) -> Result<Query, CouchbaseLiteError> {
+        let _open = self.check_open()?;
         let n1ql_query_language: ffi::CBLQueryLanguage = 1;
-        let query_string = to_ptr(n1ql_query);
+        let query_string = to_cstring(&n1ql_query)?;
         let mut out_error_pos: ::std::os::raw::c_int = 0;
         let mut error = init_error();
-        let query = unsafe { ffi::CBLQuery_New(self.db, n1ql_query_language, query_string, &mut out_error_pos, &mut error) };
+        let query = unsafe { ffi::CBLQuery_New(self.db, n1ql_query_language, query_string.as_ptr(), &mut out_error_pos, &mut error) };
         if error.code == 0 {
             Ok(Query { query })
         } else {
-            Err(CouchbaseLiteError::CannotCreateNewQuery(error))
+            Err(CouchbaseLiteError::CannotCreateNewQuery(NativeError::new(error)))
         }
     }
 
-    /// Returns the database's name.
+    /// Returns the database's name, even once it is closed.
     pub fn get_name(&self) -> String {
-        let name = unsafe { ffi::CBLDatabase_Name(self.db) };
-        to_string(name)
+        self.shared.name.clone()
     }
 
-    /// Returns the database's full filesystem path.
+    /// Returns the database's full filesystem path, even once it is closed.
     pub fn get_path(&self) -> String {
-        let path = unsafe { ffi::CBLDatabase_Path(self.db) };
-        to_string(path)
+        self.shared.path.clone()
     }
 
-    /// Returns the number of documents in the database.
+    /// Returns the number of documents in the database, or 0 once it is closed.
     pub fn count(&self) -> u64 {
-        unsafe { ffi::CBLDatabase_Count(self.db) }
+        match self.check_open() {
+            Ok(_open) => unsafe { ffi::CBLDatabase_Count(self.db) },
+            Err(_) => 0,
+        }
     }
 
-    /// Executes an operation as a "batch", similar to a transaction.
-    pub fn in_batch(&self, unit: &dyn Fn() -> ()) -> Result<(), CouchbaseLiteError> {
+    /// Performs database maintenance, e.g. compacting the file after many documents were deleted,
+    /// and reports the size of the database files before and after the operation.
+    pub fn perform_maintenance(&self, maintenance_type: MaintenanceType) -> Result<MaintenanceReport, CouchbaseLiteError> {
+        drop(self.check_open()?);
+        let path = self.get_path();
+        let size_before = database_size(Path::new(&path)).map_err(CouchbaseLiteError::CannotReadDatabaseSize)?;
         let mut error = init_error();
-        let status = unsafe { ffi::CBLDatabase_BeginBatch(self.db, &mut error) };
+        let status = self.write(None, || unsafe { ffi::CBLDatabase_PerformMaintenance(self.db, maintenance_type.to_native(), &mut error) })?;
         if error.code == 0 && status {
-            (unit)();
-            let status = unsafe { ffi::CBLDatabase_EndBatch(self.db, &mut error) };
-            if error.code == 0 && status {
-                return Ok(());
-            }
+            let size_after = database_size(Path::new(&path)).map_err(CouchbaseLiteError::CannotReadDatabaseSize)?;
+            Ok(MaintenanceReport {
+                maintenance_type,
+                size_before,
+                size_after,
+            })
+        } else {
+            Err(CouchbaseLiteError::CannotPerformMaintenance(NativeError::new(error)))
         }
-        Err(CouchbaseLiteError::ErrorInBatch(error))
+    }
+
+    /// Registers a listener called with the IDs of the documents changed in the database.
+    ///
+    /// The listener may be called on any thread, even on several at once; a panic inside it is
+    /// caught, so that it doesn't unwind into the C library. It is removed when the returned token is dropped.
+    pub fn add_change_listener<F>(&self, listener: F) -> Result<ListenerToken, CouchbaseLiteError>
+    where
+        F: Fn(&Database, &[String]) + Send + Sync + 'static,
+    {
+        let _open = self.check_open()?;
+        let context = self.listener_context(Box::new(listener) as DatabaseChangeCallback);
+        let token = unsafe { ffi::CBLDatabase_AddChangeListener(self.db, Some(database_change_listener), context.as_ptr()) };
+        Ok(ListenerToken::new(token, context))
+    }
+
+    /// Registers a listener called with the document ID whenever the given document changes.
+    ///
+    /// Same threading and unregistration rules as `add_change_listener`.
+    pub fn add_document_change_listener<F>(&self, doc_id: &str, listener: F) -> Result<ListenerToken, CouchbaseLiteError>
+    where
+        F: Fn(&Database, &str) + Send + Sync + 'static,
+    {
+        let _open = self.check_open()?;
+        let context = self.listener_context(Box::new(listener) as DocumentChangeCallback);
+        let doc_id = to_cstring(doc_id)?;
+        let token =
+            unsafe { ffi::CBLDatabase_AddDocumentChangeListener(self.db, doc_id.as_ptr(), Some(document_change_listener), context.as_ptr()) };
+        Ok(ListenerToken::new(token, context))
+    }
+
+    /// Registers a listener that sends the IDs of changed documents into a channel, so that an event
+    /// loop can receive database changes alongside its other events. The listener is removed when the
+    /// returned token is dropped; changes are silently discarded once the receiver is gone.
+    ///
+    /// Other channel implementations (e.g. crossbeam) can be fed the same way through `add_change_listener`.
+    pub fn add_change_channel(&self, sender: mpsc::Sender<Vec<String>>) -> Result<ListenerToken, CouchbaseLiteError> {
+        self.add_change_listener(move |_, doc_ids| {
+            let _ = sender.send(doc_ids.to_vec());
+        })
+    }
+
+    /// Switches the database to buffered notifications: instead of calling listeners as soon as
+    /// changes happen, on arbitrary threads, the database calls `ready` once notifications are
+    /// pending and the application delivers them by calling `send_pending_notifications` on the
+    /// thread of its choice.
+    ///
+    /// `ready` may itself be called on any thread and should only wake up the application's event loop.
+    /// Calling this again replaces `ready`, but the previous callback is only freed with the database.
+    pub fn buffer_notifications<F>(&self, ready: F) -> Result<(), CouchbaseLiteError>
+    where
+        F: Fn(&Database) + Send + Sync + 'static,
+    {
+        let _open = self.check_open()?;
+        let context = self.listener_context(Box::new(ready) as NotificationsReadyCallback);
+        unsafe { ffi::CBLDatabase_BufferNotifications(self.db, Some(notifications_ready), context.as_ptr()) };
+        self.shared.notifications_ready.lock().unwrap().push(context);
+        Ok(())
+    }
+
+    /// Calls the listeners of all pending notifications, on the current thread.
+    /// Only useful after `buffer_notifications` was called.
+    ///
+    /// The listeners may use the database. Closing it from another thread waits until they return.
+    pub fn send_pending_notifications(&self) -> Result<(), CouchbaseLiteError> {
+        let _open = self.check_open()?;
+        unsafe { ffi::CBLDatabase_SendNotifications(self.db) };
+        Ok(())
+    }
+
+    /// Executes an operation as a "batch", similar to a transaction.
+    ///
+    /// A batch is not a transaction: the changes are committed even if the operation fails or
+    /// panics (the panic is then propagated). Batches and transactions can be nested, see
+    /// `transaction`: a nested batch that fails is rolled back like a nested transaction.
+    pub fn in_batch(&self, unit: &dyn Fn() -> ()) -> Result<(), CouchbaseLiteError> {
+        self.run_batch(
+            || {
+                (unit)();
+                Ok(())
+            },
+            true,
+            CouchbaseLiteError::ErrorInBatch,
+        )
     }
 
     /// Executes an operation as a "batch", similar to a transaction. The operation function can return a result.
+    ///
+    /// As with `in_batch`, the changes are committed even if the operation returns an error or panics.
     pub fn in_batch_with_result<T>(&self, unit: &dyn Fn() -> Result<T, CouchbaseLiteError>) -> Result<T, CouchbaseLiteError> {
+        self.run_batch(unit, true, CouchbaseLiteError::ErrorInBatch)
+    }
+
+    /// Returns true while the current thread runs a batch or transaction on this database.
+    pub fn in_transaction(&self) -> bool {
+        let batch = self.shared.batch.lock().unwrap();
+        batch.owner == Some(thread::current().id()) && !batch.savepoints.is_empty()
+    }
+
+    /// Encrypts, re-keys or decrypts (when `new_key` is `None`) the database file.
+    ///
+    /// The database must have been opened with its current key.
+    pub fn change_encryption_key(&self, new_key: Option<&EncryptionKey>) -> Result<(), CouchbaseLiteError> {
         let mut error = init_error();
-        let status = unsafe { ffi::CBLDatabase_BeginBatch(self.db, &mut error) };
+        let key = new_key.map(|key| EncryptionKey::to_native(Some(key)));
+        let key_ptr = key.as_ref().map_or(ptr::null(), |key| key as *const ffi::CBLEncryptionKey);
+        let status = self.write(None, || unsafe { ffi::CBLDatabase_Rekey(self.db, key_ptr, &mut error) })?;
         if error.code == 0 && status {
-            let result = (unit)();
-            let status = unsafe { ffi::CBLDatabase_EndBatch(self.db, &mut error) };
+            Ok(())
+        } else {
+            Err(CouchbaseLiteError::CannotChangeEncryptionKey(NativeError::new(error)))
+        }
+    }
+
+    /// Executes `unit` inside a transaction: its changes are committed if it returns `Ok`, and
+    /// rolled back if it returns `Err` or panics (the panic is then propagated).
+    ///
+    /// Unlike `in_batch`, nothing written by a failed unit is kept. As the C library can't abort a
+    /// batch, the documents the unit saved, deleted or purged are restored before the batch is
+    /// committed: the ones it created are purged, the others get a new revision with their previous
+    /// content. Listeners may be notified of these documents. Only the writes made through `Database`
+    /// are rolled back, and created indexes are kept.
+    ///
+    /// Transactions and batches can be nested, so helpers can use them without knowing whether
+    /// their caller already started one. Only the outermost one commits. A nested one acts as a
+    /// savepoint: if it fails, only its own changes are rolled back, and the enclosing one goes on
+    /// and may still commit.
+    ///
+    /// Fails with `ErrorInTransaction` if the C batch can't begin or end, or the changes can't be
+    /// rolled back.
+    pub fn transaction<T, E, F>(&self, unit: F) -> Result<T, E>
+    where
+        F: FnOnce(&Database) -> Result<T, E>,
+        E: From<CouchbaseLiteError>,
+    {
+        self.run_batch(|| unit(self), false, CouchbaseLiteError::ErrorInTransaction)
+    }
+
+    fn run_batch<T, E, F>(&self, unit: F, commit_on_failure: bool, batch_error: fn(NativeError) -> CouchbaseLiteError) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+        E: From<CouchbaseLiteError>,
+    {
+        let outermost = self.begin_batch(batch_error)?;
+        let result = panic::catch_unwind(AssertUnwindSafe(unit));
+        // Only an outermost batch commits on failure: nested ones are savepoints
+        let commit = match result {
+            Ok(Ok(_)) => true,
+            Ok(Err(_)) | Err(_) => commit_on_failure && outermost,
+        };
+        let ended = self.end_batch(commit, batch_error);
+        match result {
+            Err(cause) => panic::resume_unwind(cause),
+            Ok(result) => {
+                ended?;
+                result
+            }
+        }
+    }
+
+    /// Enters a batch, beginning the C batch if it is the outermost one, in which case it returns true.
+    /// Waits while another thread runs a batch.
+    fn begin_batch(&self, batch_error: fn(NativeError) -> CouchbaseLiteError) -> Result<bool, CouchbaseLiteError> {
+        let current = thread::current().id();
+        {
+            let mut batch = self.shared.batch.lock().unwrap();
+            while batch.owner.map_or(false, |owner| owner != current) || batch.writers.iter().any(|writer| *writer != current) {
+                batch = self.shared.batch_ended.wait(batch).unwrap();
+            }
+            if !batch.savepoints.is_empty() {
+                let savepoint = batch.snapshots.len();
+                batch.savepoints.push(savepoint);
+                return Ok(false);
+            }
+            // Claimed before the C batch begins, as the lock isn't held meanwhile
+            batch.owner = Some(current);
+            batch.savepoints.push(0);
+        }
+        let began = self.check_open().and_then(|_open| {
+            let mut error = init_error();
+            let status = unsafe { ffi::CBLDatabase_BeginBatch(self.db, &mut error) };
             if error.code == 0 && status {
-                return result;
+                Ok(true)
+            } else {
+                Err(batch_error(NativeError::new(error)))
+            }
+        });
+        if began.is_err() {
+            self.release_batch();
+        }
+        began
+    }
+
+    /// Leaves a batch, rolling back its changes unless `commit`, and ends the C batch if it is the
+    /// outermost one.
+    ///
+    /// No lock is held while the C batch ends, as the C library may then call listeners, which may
+    /// use the database.
+    fn end_batch(&self, commit: bool, batch_error: fn(NativeError) -> CouchbaseLiteError) -> Result<(), CouchbaseLiteError> {
+        let (snapshots, outermost) = {
+            let mut batch = self.shared.batch.lock().unwrap();
+            let savepoint = batch.savepoints.pop().expect("no batch to end");
+            let snapshots = if commit { Vec::new() } else { batch.snapshots.split_off(savepoint) };
+            (snapshots, batch.savepoints.is_empty())
+        };
+        let result = self.check_open().and_then(|_open| {
+            let mut failure = None;
+            // Latest first, so that each document ends up as it was at the savepoint
+            for snapshot in snapshots.iter().rev() {
+                if let Err(error) = unsafe { snapshot.restore(self.db) } {
+                    failure.get_or_insert(error);
+                }
+            }
+            if outermost {
+                let mut error = init_error();
+                let status = unsafe { ffi::CBLDatabase_EndBatch(self.db, &mut error) };
+                if error.code != 0 || !status {
+                    failure.get_or_insert(error);
+                }
+            }
+            match failure {
+                None => Ok(()),
+                Some(error) => Err(batch_error(NativeError::new(error))),
+            }
+        });
+        if outermost {
+            self.release_batch();
+        }
+        result
+    }
+
+    /// Lets other threads start batches and write again once the outermost batch ended.
+    fn release_batch(&self) {
+        let mut batch = self.shared.batch.lock().unwrap();
+        batch.owner = None;
+        batch.savepoints.clear();
+        batch.snapshots.clear();
+        self.shared.batch_ended.notify_all();
+    }
+
+    /// Runs a write. Waits while another thread runs a batch, so that the write doesn't become part
+    /// of it (and isn't rolled back with it), and keeps other threads from starting one until the
+    /// write is done. In a batch of the current thread, takes a snapshot of the document `doc_id`
+    /// first, so that the batch can be rolled back.
+    ///
+    /// Fails with `DatabaseNotOpen` if the database was closed in the meantime.
+    fn write<T, F: FnOnce() -> T>(&self, doc_id: Option<&CStr>, write: F) -> Result<T, CouchbaseLiteError> {
+        let current = thread::current().id();
+        let in_batch = {
+            let mut batch = self.shared.batch.lock().unwrap();
+            while batch.owner.map_or(false, |owner| owner != current) {
+                batch = self.shared.batch_ended.wait(batch).unwrap();
             }
+            batch.writers.push(current);
+            !batch.savepoints.is_empty()
+        };
+        // Only held once done waiting, so that the thread running the batch can close the database
+        let result = self.check_open().map(|_open| {
+            let snapshot = doc_id.filter(|_| in_batch).map(|doc_id| unsafe { Snapshot::take(self.db, doc_id) });
+            (write(), snapshot)
+        });
+        let mut batch = self.shared.batch.lock().unwrap();
+        if let Some(position) = batch.writers.iter().position(|writer| *writer == current) {
+            batch.writers.swap_remove(position);
+        }
+        self.shared.batch_ended.notify_all();
+        result.map(|(value, snapshot)| {
+            batch.snapshots.extend(snapshot);
+            value
+        })
+    }
+
+    /// Returns true if `other` is this handle or one of its clones.
+    pub(crate) fn is_same(&self, other: &Database) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
+
+    /// Returns false once the database was closed or deleted, through this handle or any of its clones.
+    pub fn is_open(&self) -> bool {
+        self.shared.open.lock().unwrap().open
+    }
+
+    /// Fails with `DatabaseNotOpen` if the database was closed. Otherwise the returned guard keeps
+    /// the database open until dropped, so it must be held across the calls to the C library.
+    ///
+    /// Waits while another thread is closing the database, unless the current thread already holds
+    /// a guard.
+    pub(crate) fn check_open(&self) -> Result<OpenGuard<'_>, CouchbaseLiteError> {
+        let current = thread::current().id();
+        let mut state = self.shared.open.lock().unwrap();
+        while state.closing > 0 && !state.users.contains(&current) {
+            state = self.shared.released.wait(state).unwrap();
+        }
+        if state.open {
+            state.users.push(current);
+            Ok(OpenGuard { shared: &self.shared })
+        } else {
+            Err(CouchbaseLiteError::DatabaseNotOpen)
+        }
+    }
+
+    /// Waits until the other threads dropped their `OpenGuard`, and returns the state to close the
+    /// database with. The guards of the current thread can't be waited for, e.g. in a listener
+    /// closing the database.
+    fn lock_for_closing(&self) -> MutexGuard<'_, OpenState> {
+        let current = thread::current().id();
+        let mut state = self.shared.open.lock().unwrap();
+        state.closing += 1;
+        while state.users.iter().any(|user| *user != current) {
+            state = self.shared.released.wait(state).unwrap();
         }
-        Err(CouchbaseLiteError::ErrorInBatch(error))
+        state.closing -= 1;
+        // Threads waiting in `check_open` go on once the database is closed
+        self.shared.released.notify_all();
+        state
     }
 
     pub fn close(&self) -> Result<(), CouchbaseLiteError> {
+        let mut state = self.lock_for_closing();
+        if !state.open {
+            return Err(CouchbaseLiteError::DatabaseNotOpen);
+        }
         let mut error = init_error();
         let status = unsafe { ffi::CBLDatabase_Close(self.db, &mut error) };
         if error.code == 0 && status {
-            self.open.set(false);
+            state.open = false;
             Ok(())
         } else {
-            Err(CouchbaseLiteError::CannotCloseDatabase(error))
+            Err(CouchbaseLiteError::CannotCloseDatabase(NativeError::new(error)))
         }
     }
 
     /// Deletes the (opened) database. After the database is deleted, the database object (self) is closed.
     pub fn delete(&self) -> Result<(), CouchbaseLiteError> {
+        let mut state = self.lock_for_closing();
+        if !state.open {
+            return Err(CouchbaseLiteError::DatabaseNotOpen);
+        }
         let mut error = init_error();
         let status = unsafe { ffi::CBLDatabase_Delete(self.db, &mut error) };
         if error.code == 0 && status {
-            self.open.set(false);
+            state.open = false;
             Ok(())
         } else {
-            Err(CouchbaseLiteError::CannotDeleteDatabase(error))
+            Err(CouchbaseLiteError::CannotDeleteDatabase(NativeError::new(error)))
+        }
+    }
+}
+
+/// State shared with `save_conflict_handler` for the duration of `CBLDatabase_SaveDocumentResolving`.
+struct ConflictContext<'a> {
+    handler: &'a mut dyn FnMut(&mut Document, Option<&Document>) -> bool,
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+unsafe extern "C" fn save_conflict_handler(
+    context: *mut c_void,
+    document_being_saved: *mut ffi::CBLDocument,
+    conflicting_document: *const ffi::CBLDocument,
+) -> bool {
+    let context = &mut *(context as *mut ConflictContext);
+    // Both documents are owned by the C library for the duration of the call. The conflicting one
+    // is immutable: it is only lent as a `&Document`, whose methods don't modify it
+    let mut mine = Document::borrowed(document_being_saved);
+    let theirs = if conflicting_document.is_null() {
+        None
+    } else {
+        Some(Document::borrowed(conflicting_document as *mut ffi::CBLDocument))
+    };
+    let handler = &mut context.handler;
+    match panic::catch_unwind(AssertUnwindSafe(|| handler(&mut mine, theirs.as_deref()))) {
+        Ok(accepted) => accepted,
+        Err(cause) => {
+            context.panic = Some(cause);
+            false
+        }
+    }
+}
+
+fn is_conflict(error: &ffi::CBLError) -> bool {
+    // kCBLDomain, kCBLErrorConflict
+    error.domain == 1 && error.code == 8
+}
+
+fn purge_result<F: FnOnce() -> String>(status: bool, error: ffi::CBLError, doc_id: F) -> Result<(), CouchbaseLiteError> {
+    match (error.domain, error.code) {
+        (_, 0) if status => Ok(()),
+        // kCBLErrorNotFound
+        (1, 7) => Err(CouchbaseLiteError::DocumentNotFound(doc_id())),
+        _ => Err(CouchbaseLiteError::CannotPurgeDocument(NativeError::for_document(error, doc_id()))),
+    }
+}
+
+fn retain(db: *mut ffi::CBLDatabase) -> *mut ffi::CBLDatabase {
+    unsafe { ffi::CBL_Retain(db as *mut ffi::CBLRefCounted) as *mut ffi::CBLDatabase }
+}
+
+impl Clone for Database {
+    fn clone(&self) -> Self {
+        Database {
+            db: retain(self.db),
+            shared: Arc::clone(&self.shared),
         }
     }
 }
 
+impl fmt::Debug for Database {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Database").field("db", &self.db).field("open", &self.is_open()).finish()
+    }
+}
+
 impl Drop for Database {
     fn drop(&mut self) {
-        if self.open.get() {
-            let _ = self.close();
-            self.open.set(false);
-        }
+        // The shared state, dropped afterwards with the last clone, closes the database
         unsafe { ffi::CBL_Release(self.db as *mut ffi::CBLRefCounted) };
     }
 }
 
-
 #[cfg(test)]
 mod tests {
+    use crate::Concurrency;
+    use crate::CouchbaseLiteError;
     use crate::Database;
+    use crate::DatabaseConfig;
     use crate::Document;
+    use crate::EncryptionKey;
+    use crate::MaintenanceType;
+    use crate::{UpdateAction, UpdateOptions};
     use serde::{Deserialize, Serialize};
     use std::fs;
-    use std::time::Instant;
+    use std::os::raw::{c_char, c_void};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
     use uuid::Uuid;
 
     use serde_json::json;
@@ -291,7 +1141,7 @@ mod tests {
         let database = open_database();
         let doc_id = String::from("foo");
         {
-            let doc = Document::new(doc_id.clone());
+            let mut doc = Document::new(doc_id.clone());
             doc.set_value(String::from("Howdy!"), String::from("greeting"));
             assert_eq!("{\"greeting\":\"Howdy!\"}", doc.jsonify());
 
@@ -315,7 +1165,7 @@ mod tests {
     fn test_index() {
         let database = open_database();
         for i in 0..10000 {
-            let doc = Document::new(format!("id_{}", i));
+            let mut doc = Document::new(format!("id_{}", i));
             doc.set_value(format!("Howdy{}!", i), String::from("greeting"));
             let saved = database.save_document(doc);
             assert!(saved.is_ok());
@@ -353,7 +1203,7 @@ mod tests {
 
         let database = open_database();
         let doc_id = String::from("foo");
-        let doc = Document::new(doc_id.clone());
+        let mut doc = Document::new(doc_id.clone());
         doc.fill(serde_json::to_string_pretty(&person).unwrap()).unwrap();
         assert_eq!("{\"first_name\":\"James\",\"last_name\":\"Bomb\"}", doc.jsonify());
 
@@ -366,17 +1216,56 @@ mod tests {
     }
 
     #[test]
-    fn update_existing_document_with_existing_property() {
-        let database = open_database();
-        let doc_id = String::from("foo");
-        {
-            let doc = Document::new(doc_id.clone());
-            doc.set_value(String::from("val1"), String::from("prop1"));
-            assert_eq!("{\"prop1\":\"val1\"}", doc.jsonify());
+    fn put_and_get_typed_document() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        pub struct Person {
+            pub first_name: String,
+            pub last_name: String,
+            pub age: u32,
+        }
+        let person = Person {
+            first_name: "James".to_string(),
+            last_name: "Bomb".to_string(),
+            age: 42,
+        };
+
+        let database = open_database();
+        assert!(database.get::<Person>("foo").unwrap().is_none());
+
+        let saved = database.put("foo", &person).unwrap();
+        assert_eq!("foo", saved.id);
+        assert_eq!(1, saved.sequence);
+        assert!(saved.revision_id.starts_with("1-"));
+        assert_eq!(&person, saved.value);
+
+        let fetched = database.get::<Person>("foo").unwrap().unwrap();
+        assert_eq!(saved.revision_id, fetched.revision_id);
+        assert_eq!(person, fetched.value);
+
+        let older = Person { age: 43, ..fetched.into_value() };
+        let updated = database.put("foo", &older).unwrap();
+        assert_eq!(2, updated.sequence);
+        assert!(updated.revision_id.starts_with("2-"));
+        assert_eq!(43, database.get::<Person>("foo").unwrap().unwrap().value.age);
+
+        match database.get::<Vec<String>>("foo") {
+            Err(error @ CouchbaseLiteError::CannotDeserializeDocument { .. }) => assert_eq!(Some("foo"), error.doc_id()),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn update_existing_document_with_existing_property() {
+        let database = open_database();
+        let doc_id = String::from("foo");
+        {
+            let mut doc = Document::new(doc_id.clone());
+            doc.set_value(String::from("val1"), String::from("prop1"));
+            assert_eq!("{\"prop1\":\"val1\"}", doc.jsonify());
 
             let saved = database.save_document(doc);
             assert_eq!(true, saved.is_ok());
-            let saved = saved.unwrap();
+            let mut saved = saved.unwrap();
             assert_eq!(doc_id, saved.id());
             assert_eq!(1, saved.sequence());
             assert_eq!("{\"prop1\":\"val1\"}", saved.jsonify());
@@ -401,7 +1290,7 @@ mod tests {
         database.in_batch(&|| {
             {
                 // Create document
-                let doc = Document::new(doc_id.clone());
+                let mut doc = Document::new(doc_id.clone());
                 doc.set_value(String::from("val1"), String::from("prop1"));
                 assert_eq!("{\"prop1\":\"val1\"}", doc.jsonify());
                 let saved = database.save_document(doc);
@@ -415,7 +1304,7 @@ mod tests {
                 // Update Document
                 let doc = database.get_document(doc_id.clone());
                 assert_eq!(true, doc.is_some());
-                let doc = doc.unwrap();
+                let mut doc = doc.unwrap();
                 // Add new property
                 doc.set_value(String::from("val2"), String::from("prop2"));
                 database.save_document(doc).unwrap();
@@ -448,7 +1337,7 @@ mod tests {
         let database = open_database();
         {
             // Create Document
-            let doc = Document::new(doc_id.clone());
+            let mut doc = Document::new(doc_id.clone());
             let data = Struct1 {
                 prop1: "val1".to_string(),
                 prop2: None,
@@ -471,7 +1360,7 @@ mod tests {
                 // Update Document
                 let doc = database.get_document(doc_id.clone());
                 assert_eq!(true, doc.is_some());
-                let doc = doc.unwrap();
+                let mut doc = doc.unwrap();
                 // Add new property
                 let data = Struct1 {
                     prop1: "val1".to_string(),
@@ -497,7 +1386,7 @@ mod tests {
                 // Update Document
                 let doc = database.get_document(doc_id.clone());
                 assert_eq!(true, doc.is_some());
-                let doc = doc.unwrap();
+                let mut doc = doc.unwrap();
                 // Add new property
                 let data = Struct1 {
                     prop1: "val1".to_string(),
@@ -524,7 +1413,7 @@ mod tests {
                 // Update Document
                 let doc = database.get_document(doc_id.clone());
                 assert_eq!(true, doc.is_some());
-                let doc = doc.unwrap();
+                let mut doc = doc.unwrap();
                 // Add new property
                 let data = Struct1 {
                     prop1: "val1".to_string(),
@@ -558,7 +1447,7 @@ mod tests {
         let database = open_database();
         let doc_id = String::from("foo");
         {
-            let doc = Document::new(doc_id.clone());
+            let mut doc = Document::new(doc_id.clone());
             doc.fill(json!({"prop1": "val1"}).to_string()).unwrap();
             let saved = database.save_document(doc);
             assert_eq!(true, saved.is_ok());
@@ -590,7 +1479,7 @@ mod tests {
                 pub prop1: String,
             }
             let database = Database::open(test_dir.clone(), &database_name).unwrap();
-            let doc = Document::new(doc_id.clone());
+            let mut doc = Document::new(doc_id.clone());
             let data = Struct1 { prop1: "val1".to_string() };
             doc.fill(serde_json::to_string_pretty(&data).unwrap()).unwrap();
             let saved = database.save_document(doc);
@@ -622,7 +1511,7 @@ mod tests {
         let database = open_database();
         let doc_id = String::from("foo");
         {
-            let doc = Document::new(doc_id.clone());
+            let mut doc = Document::new(doc_id.clone());
             doc.fill(json!({"prop1": "val1"}).to_string()).unwrap();
             let saved = database.save_document(doc);
             assert_eq!(true, saved.is_ok());
@@ -645,4 +1534,1111 @@ mod tests {
         assert!(document.is_none());
     }
 
+    #[test]
+    fn read_blob() {
+        let database = open_database();
+        let doc = Document::new(String::from("foo"));
+        let content = b"Hello, blob!";
+        unsafe {
+            let contents = ffi::FLSlice {
+                buf: content.as_ptr() as *const c_void,
+                size: content.len(),
+            };
+            let blob = ffi::CBLBlob_CreateWithData(b"text/plain\0".as_ptr() as *const c_char, contents);
+            let properties = ffi::CBLDocument_MutableProperties(doc.doc);
+            let key = ffi::FLSlice {
+                buf: b"attachment".as_ptr() as *const c_void,
+                size: 10,
+            };
+            ffi::FLSlot_SetBlob(ffi::FLMutableDict_Set(properties, key), blob);
+            ffi::CBL_Release(blob as *mut ffi::CBLRefCounted);
+        }
+        database.save_document(doc).unwrap();
+
+        let doc = database.get_document(String::from("foo")).unwrap();
+        let blob = doc.get_blob("attachment").unwrap();
+        assert_eq!(content.len() as u64, blob.length());
+        assert_eq!(Some("text/plain"), blob.content_type().as_deref());
+        assert!(blob.digest().starts_with("sha1-"));
+        assert_eq!(&content[..], &blob.content().unwrap()[..]);
+        assert!(doc.get_blob("missing").is_none());
+    }
+
+    #[test]
+    fn open_missing_database_without_create() {
+        let config = DatabaseConfig::new(test_dir()).create(false);
+        match Database::open_with("testdb", &config) {
+            Err(CouchbaseLiteError::DatabaseNotFound(_)) => {}
+            other => panic!("expected DatabaseNotFound, got {:?}", other.map(|db| db.get_name())),
+        }
+    }
+
+    #[test]
+    fn open_existing_database_read_only() {
+        let database_name = String::from("testdb");
+        let test_dir = test_dir();
+        {
+            let database = Database::open(test_dir.clone(), &database_name).unwrap();
+            let mut doc = Document::new(String::from("foo"));
+            doc.set_value(String::from("val1"), String::from("prop1"));
+            database.save_document(doc).unwrap();
+            database.close().unwrap();
+        }
+        {
+            let config = DatabaseConfig::new(test_dir).create(false).read_only(true);
+            let database = Database::open_with(&database_name, &config).unwrap();
+            assert_eq!(1, database.count());
+            let mut doc = Document::new(String::from("bar"));
+            doc.set_value(String::from("val1"), String::from("prop1"));
+            assert!(database.save_document(doc).is_err());
+        }
+    }
+
+    #[test]
+    #[ignore] // requires an Enterprise Edition build of libCouchbaseLiteC
+    fn encrypted_database() {
+        let database_name = String::from("testdb");
+        let test_dir = test_dir();
+        let key = EncryptionKey::from_password("secret").unwrap();
+        {
+            let config = DatabaseConfig::new(test_dir.clone()).encryption_key(key.clone());
+            let database = Database::open_with(&database_name, &config).unwrap();
+            let mut doc = Document::new(String::from("foo"));
+            doc.set_value(String::from("val1"), String::from("prop1"));
+            database.save_document(doc).unwrap();
+            database.close().unwrap();
+        }
+        {
+            let config = DatabaseConfig::new(test_dir.clone()).encryption_key(EncryptionKey::from_bytes([1; 32]));
+            match Database::open_with(&database_name, &config) {
+                Err(CouchbaseLiteError::WrongEncryptionKey(_)) => {}
+                other => panic!("expected WrongEncryptionKey, got {:?}", other.map(|db| db.get_name())),
+            }
+        }
+        {
+            let config = DatabaseConfig::new(test_dir.clone()).encryption_key(key);
+            let database = Database::open_with(&database_name, &config).unwrap();
+            assert_eq!(1, database.count());
+            database.change_encryption_key(None).unwrap();
+            database.close().unwrap();
+        }
+        {
+            let database = Database::open(test_dir, &database_name).unwrap();
+            assert_eq!(1, database.count());
+        }
+    }
+
+    #[test]
+    fn open_corrupt_database() {
+        let database_name = String::from("testdb");
+        let test_dir = test_dir();
+        let database_dir = format!("{}/{}.cblite2", test_dir, database_name);
+        fs::create_dir_all(&database_dir).unwrap();
+        fs::write(format!("{}/db.sqlite3", database_dir), vec![0x42; 4096]).unwrap();
+        match Database::open(test_dir, &database_name) {
+            Err(CouchbaseLiteError::DatabaseCorrupt(_)) => {}
+            other => panic!("expected DatabaseCorrupt, got {:?}", other.map(|db| db.get_name())),
+        }
+    }
+
+    #[test]
+    fn exists_and_delete_named() {
+        let database_name = String::from("testdb");
+        let test_dir = test_dir();
+        assert!(!Database::exists(&database_name, &test_dir));
+        {
+            let database = Database::open(test_dir.clone(), &database_name).unwrap();
+            database.close().unwrap();
+        }
+        assert!(Database::exists(&database_name, &test_dir));
+        assert_eq!(true, Database::delete_named(&database_name, &test_dir).unwrap());
+        assert!(!Database::exists(&database_name, &test_dir));
+        assert_eq!(false, Database::delete_named(&database_name, &test_dir).unwrap());
+    }
+
+    #[test]
+    fn copy_prebuilt_database() {
+        let prebuilt_dir = test_dir();
+        let prebuilt_path = {
+            let database = Database::open(prebuilt_dir, "prebuilt").unwrap();
+            let mut doc = Document::new(String::from("foo"));
+            doc.set_value(String::from("val1"), String::from("prop1"));
+            database.save_document(doc).unwrap();
+            let path = database.get_path();
+            database.close().unwrap();
+            path
+        };
+
+        let test_dir = test_dir();
+        let config = DatabaseConfig::new(test_dir.clone());
+        Database::copy(&prebuilt_path, "testdb", &config).unwrap();
+        assert!(Database::exists("testdb", &test_dir));
+
+        let database = Database::open_with("testdb", &config).unwrap();
+        assert_eq!(1, database.count());
+        assert!(database.get_document(String::from("foo")).is_some());
+    }
+
+    #[test]
+    fn compact_database() {
+        let database = open_database();
+        for i in 0..1000 {
+            let mut doc = Document::new(format!("id_{}", i));
+            doc.set_value(format!("Howdy{}!", i), String::from("greeting"));
+            database.save_document(doc).unwrap();
+        }
+        for i in 0..1000 {
+            let doc = database.get_document(format!("id_{}", i)).unwrap();
+            database.delete_document(doc).unwrap();
+        }
+
+        let report = database.perform_maintenance(MaintenanceType::Compact).unwrap();
+        assert_eq!(MaintenanceType::Compact, report.maintenance_type);
+        assert!(report.size_before > 0);
+        assert!(report.size_after <= report.size_before);
+
+        let report = database.perform_maintenance(MaintenanceType::IntegrityCheck).unwrap();
+        assert_eq!(MaintenanceType::IntegrityCheck, report.maintenance_type);
+    }
+
+    #[test]
+    fn database_change_listener() {
+        let database = open_database();
+        let (sender, receiver) = mpsc::channel();
+        let token = database
+            .add_change_listener(move |database, doc_ids| {
+                sender.send((database.get_name(), doc_ids.to_vec())).unwrap();
+            })
+            .unwrap();
+
+        let mut doc = Document::new(String::from("foo"));
+        doc.set_value(String::from("val1"), String::from("prop1"));
+        database.save_document(doc).unwrap();
+        let (name, doc_ids) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!("testdb", name);
+        assert_eq!(vec![String::from("foo")], doc_ids);
+
+        drop(token);
+        let mut doc = Document::new(String::from("bar"));
+        doc.set_value(String::from("val1"), String::from("prop1"));
+        database.save_document(doc).unwrap();
+        assert!(receiver.recv_timeout(Duration::from_millis(500)).is_err());
+    }
+
+    #[test]
+    fn listener_shares_database_state() {
+        let database = open_database();
+        let (sender, receiver) = mpsc::channel();
+        let _token = database
+            .add_change_listener(move |database, _| {
+                sender.send(database.clone()).unwrap();
+            })
+            .unwrap();
+
+        let mut doc = Document::new(String::from("foo"));
+        doc.set_value(String::from("val1"), String::from("prop1"));
+        database.save_document(doc).unwrap();
+        let from_listener = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(from_listener.is_same(&database));
+
+        database.close().unwrap();
+        assert!(!from_listener.is_open());
+    }
+
+    #[test]
+    fn panicking_change_listener() {
+        let database = open_database();
+        let _token = database.add_change_listener(|_, _| panic!("listener failure")).unwrap();
+        let mut doc = Document::new(String::from("foo"));
+        doc.set_value(String::from("val1"), String::from("prop1"));
+        assert!(database.save_document(doc).is_ok());
+        assert_eq!(1, database.count());
+    }
+
+    #[test]
+    fn document_change_listener() {
+        let database = open_database();
+        let (sender, receiver) = mpsc::channel();
+        let _token = database
+            .add_document_change_listener("settings", move |_, doc_id| {
+                sender.send(doc_id.to_string()).unwrap();
+            })
+            .unwrap();
+
+        // Changes to other documents are not reported
+        let mut doc = Document::new(String::from("foo"));
+        doc.set_value(String::from("val1"), String::from("prop1"));
+        database.save_document(doc).unwrap();
+        assert!(receiver.recv_timeout(Duration::from_millis(500)).is_err());
+
+        let mut doc = Document::new(String::from("settings"));
+        doc.set_value(String::from("dark"), String::from("theme"));
+        database.save_document(doc).unwrap();
+        assert_eq!("settings", receiver.recv_timeout(Duration::from_secs(5)).unwrap());
+
+        let doc = database.get_document(String::from("settings")).unwrap();
+        database.delete_document(doc).unwrap();
+        assert_eq!("settings", receiver.recv_timeout(Duration::from_secs(5)).unwrap());
+    }
+
+    #[test]
+    fn buffered_notifications() {
+        let database = open_database();
+        let (ready_sender, ready_receiver) = mpsc::channel();
+        database
+            .buffer_notifications(move |_| {
+                ready_sender.send(()).unwrap();
+            })
+            .unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let _token = database.add_change_channel(sender).unwrap();
+
+        let mut doc = Document::new(String::from("foo"));
+        doc.set_value(String::from("val1"), String::from("prop1"));
+        database.save_document(doc).unwrap();
+
+        // Listeners are not called until the application asks for it
+        ready_receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(receiver.try_recv().is_err());
+
+        database.send_pending_notifications().unwrap();
+        assert_eq!(vec![String::from("foo")], receiver.try_recv().unwrap());
+    }
+
+    #[test]
+    fn replace_notifications_ready_callback() {
+        let database = open_database();
+        let (first_sender, first_receiver) = mpsc::channel();
+        database
+            .buffer_notifications(move |_| {
+                first_sender.send(()).unwrap();
+            })
+            .unwrap();
+        let (ready_sender, ready_receiver) = mpsc::channel();
+        database
+            .buffer_notifications(move |database| {
+                ready_sender.send(database.is_open()).unwrap();
+            })
+            .unwrap();
+
+        let mut doc = Document::new(String::from("foo"));
+        doc.set_value(String::from("val1"), String::from("prop1"));
+        database.save_document(doc).unwrap();
+        assert!(ready_receiver.recv_timeout(Duration::from_secs(5)).unwrap());
+        assert!(first_receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn document_expiration() {
+        let database = open_database();
+        let mut doc = Document::new(String::from("foo"));
+        doc.set_value(String::from("val1"), String::from("prop1"));
+        database.save_document(doc).unwrap();
+        assert_eq!(None, database.document_expiration("foo").unwrap());
+
+        let expiration = UNIX_EPOCH + Duration::from_secs(4_102_444_800); // 2100-01-01
+        database.set_document_expiration("foo", Some(expiration)).unwrap();
+        assert_eq!(Some(expiration), database.document_expiration("foo").unwrap());
+
+        database.set_document_expiration("foo", None).unwrap();
+        assert_eq!(None, database.document_expiration("foo").unwrap());
+    }
+
+    #[test]
+    fn expired_document_is_purged() {
+        let database = open_database();
+        for doc_id in &["session", "cache"] {
+            let mut doc = Document::new(doc_id.to_string());
+            doc.set_value(String::from("ephemeral"), String::from("kind"));
+            database.save_document(doc).unwrap();
+        }
+        database.set_document_expiration("session", Some(SystemTime::now() + Duration::from_secs(1))).unwrap();
+
+        thread::sleep(Duration::from_secs(3));
+        assert!(database.get_document(String::from("session")).is_none());
+        assert!(database.get_document(String::from("cache")).is_some());
+        assert_eq!(1, database.count());
+
+        let query = database.new_query("SELECT _id AS id WHERE kind='ephemeral'".to_string()).unwrap();
+        let rs = query.execute().unwrap();
+        assert!(rs.has_next());
+        assert_eq!("cache", rs.value("id".to_string()));
+        assert!(!rs.has_next());
+    }
+
+    #[test]
+    fn create_index_error() {
+        let database = open_database();
+        match database.create_index("broken_index", "not json") {
+            Err(error @ CouchbaseLiteError::CannotCreateIndex(_)) => {
+                assert_eq!("create index", error.operation());
+                assert!(error.native().is_some());
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let database = open_database();
+        let doc = unsafe { ffi::CBLDocument_New(b"doc_\xff\0".as_ptr() as *const c_char) };
+        let doc = Document::from_raw(&database, doc);
+        assert_eq!("doc_\u{FFFD}", doc.id());
+        assert_eq!(b"doc_\xff".to_vec(), doc.id_bytes());
+
+        let slice = |bytes: &[u8]| ffi::FLSlice {
+            buf: bytes.as_ptr() as *const c_void,
+            size: bytes.len(),
+        };
+        unsafe {
+            let properties = ffi::CBLDocument_MutableProperties(doc.doc);
+            ffi::FLSlot_SetString(ffi::FLMutableDict_Set(properties, slice(b"greeting")), slice(b"Howdy\xfe!"));
+            ffi::FLSlot_SetData(ffi::FLMutableDict_Set(properties, slice(b"payload")), slice(&[0, 0xff, 0x80]));
+        }
+        database.save_document(doc).unwrap();
+
+        let query = database.new_query("SELECT _id AS id, greeting, payload".to_string()).unwrap();
+        let rs = query.execute().unwrap();
+        assert!(rs.has_next());
+        assert_eq!("doc_\u{FFFD}", rs.value("id".to_string()));
+        assert_eq!(b"doc_\xff".to_vec(), rs.value_bytes("id".to_string()));
+        assert_eq!("Howdy\u{FFFD}!", rs.value("greeting".to_string()));
+        assert_eq!(vec![0, 0xff, 0x80], rs.value_bytes("payload".to_string()));
+        assert_eq!("\u{0}\u{FFFD}\u{FFFD}", rs.value("payload".to_string()));
+        assert!(!rs.has_next());
+    }
+
+    #[test]
+    fn purge_document() {
+        let database = open_database();
+        for doc_id in &["foo", "bar"] {
+            let mut doc = Document::new(doc_id.to_string());
+            doc.set_value(String::from("val1"), String::from("prop1"));
+            database.save_document(doc).unwrap();
+        }
+
+        let doc = database.get_document(String::from("foo")).unwrap();
+        database.purge_document(&doc).unwrap();
+        assert!(database.get_document(String::from("foo")).is_none());
+
+        database.purge_document_by_id("bar").unwrap();
+        assert!(database.get_document(String::from("bar")).is_none());
+        assert_eq!(0, database.count());
+    }
+
+    #[test]
+    fn purge_document_of_other_database() {
+        let database = open_database();
+        let other_database = open_database();
+        let mut doc = Document::new(String::from("foo"));
+        doc.set_value(String::from("val1"), String::from("prop1"));
+        let saved = other_database.save_document(doc).unwrap();
+        match database.purge_document(&saved) {
+            Err(CouchbaseLiteError::DocumentFromOtherDatabase { doc_id }) => assert_eq!("foo", doc_id),
+            other => panic!("expected DocumentFromOtherDatabase, got {:?}", other),
+        }
+        assert!(other_database.get_document(String::from("foo")).is_some());
+
+        let unsaved = Document::new(String::from("bar"));
+        assert!(database.purge_document(&unsaved).is_err());
+        let unsaved = database.create_document(String::from("bar"));
+        match database.purge_document(&unsaved) {
+            Err(CouchbaseLiteError::DocumentNotFound(doc_id)) => assert_eq!("bar", doc_id),
+            other => panic!("expected DocumentNotFound, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn purge_inexisting_document() {
+        let database = open_database();
+        match database.purge_document_by_id("inexisting") {
+            Err(CouchbaseLiteError::DocumentNotFound(doc_id)) => assert_eq!("inexisting", doc_id),
+            other => panic!("expected DocumentNotFound, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn delete_document_by_id() {
+        let database = open_database();
+        let mut doc = Document::new(String::from("foo"));
+        doc.set_value(String::from("val1"), String::from("prop1"));
+        database.save_document(doc).unwrap();
+
+        database.delete_document_by_id("foo", Concurrency::LastWriteWins).unwrap();
+        assert!(database.get_document(String::from("foo")).is_none());
+        match database.delete_document_by_id("inexisting", Concurrency::LastWriteWins) {
+            Err(CouchbaseLiteError::DocumentNotFound(doc_id)) => assert_eq!("inexisting", doc_id),
+            other => panic!("expected DocumentNotFound, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn delete_document_by_id_twice() {
+        let database = open_database();
+        let mut doc = Document::new(String::from("foo"));
+        doc.set_value(String::from("val1"), String::from("prop1"));
+        database.save_document(doc).unwrap();
+
+        database.delete_document_by_id("foo", Concurrency::LastWriteWins).unwrap();
+        match database.delete_document_by_id("foo", Concurrency::LastWriteWins) {
+            Err(CouchbaseLiteError::DocumentNotFound(doc_id)) => assert_eq!("foo", doc_id),
+            other => panic!("expected DocumentNotFound, got {:?}", other),
+        }
+        assert_eq!(0, database.count());
+    }
+
+    #[test]
+    fn delete_many_documents() {
+        let database = open_database();
+        for i in 0..100 {
+            let mut doc = Document::new(format!("id_{}", i));
+            doc.set_value(format!("Howdy{}!", i), String::from("greeting"));
+            database.save_document(doc).unwrap();
+        }
+
+        let mut ids: Vec<String> = (0..50).map(|i| format!("id_{}", i)).collect();
+        ids.push(String::from("inexisting"));
+        let outcomes = database.delete_documents(&ids, Concurrency::FailOnConflict).unwrap();
+        assert_eq!(51, outcomes.len());
+        assert!(outcomes[..50].iter().all(|(_, outcome)| outcome.is_ok()));
+        assert_eq!("inexisting", outcomes[50].0);
+        assert!(outcomes[50].1.is_err());
+        assert_eq!(50, database.count());
+    }
+
+    #[test]
+    fn save_document_fail_on_conflict() {
+        let database = open_database();
+        let mut doc = Document::new(String::from("foo"));
+        doc.set_value(String::from("val1"), String::from("prop1"));
+        database.save_document(doc).unwrap();
+
+        let mut mine = database.get_document(String::from("foo")).unwrap();
+        let mut theirs = database.get_document(String::from("foo")).unwrap();
+        theirs.set_value(String::from("theirs"), String::from("prop1"));
+        database.save_document_with(theirs, Concurrency::FailOnConflict).unwrap();
+
+        mine.set_value(String::from("mine"), String::from("prop1"));
+        match database.save_document_with(mine, Concurrency::FailOnConflict) {
+            Err(CouchbaseLiteError::Conflict { doc_id }) => assert_eq!("foo", doc_id),
+            other => panic!("expected Conflict, got {:?}", other.map(|doc| doc.jsonify())),
+        }
+        assert_eq!("{\"prop1\":\"theirs\"}", database.get_document(String::from("foo")).unwrap().jsonify());
+    }
+
+    #[test]
+    fn delete_document_fail_on_conflict() {
+        let database = open_database();
+        let mut doc = Document::new(String::from("foo"));
+        doc.set_value(String::from("val1"), String::from("prop1"));
+        database.save_document(doc).unwrap();
+
+        let stale = database.get_document(String::from("foo")).unwrap();
+        let mut current = database.get_document(String::from("foo")).unwrap();
+        current.set_value(String::from("val2"), String::from("prop1"));
+        database.save_document(current).unwrap();
+
+        match database.delete_document_with(stale, Concurrency::FailOnConflict) {
+            Err(CouchbaseLiteError::Conflict { doc_id }) => assert_eq!("foo", doc_id),
+            other => panic!("expected Conflict, got {:?}", other),
+        }
+        assert!(database.get_document(String::from("foo")).is_some());
+    }
+
+    fn conflicting_documents(database: &Database) -> Document {
+        let mut doc = Document::new(String::from("foo"));
+        doc.fill(json!({"prop1": "val1"}).to_string()).unwrap();
+        database.save_document(doc).unwrap();
+
+        let mut mine = database.get_document(String::from("foo")).unwrap();
+        let mut theirs = database.get_document(String::from("foo")).unwrap();
+        theirs.fill(json!({"prop1": "val1", "prop2": "theirs"}).to_string()).unwrap();
+        database.save_document(theirs).unwrap();
+        mine.fill(json!({"prop1": "val1", "prop3": "mine"}).to_string()).unwrap();
+        mine
+    }
+
+    #[test]
+    fn save_document_with_accepting_conflict_handler() {
+        let database = open_database();
+        let mine = conflicting_documents(&database);
+        let mut calls = 0;
+        let saved = database
+            .save_document_with_conflict_handler(mine, |mine, theirs| {
+                calls += 1;
+                let theirs = theirs.unwrap();
+                assert_eq!(json!({"prop1": "val1", "prop2": "theirs"}).to_string(), theirs.jsonify());
+                mine.set_value(String::from("theirs"), String::from("prop2"));
+                true
+            })
+            .unwrap();
+        assert_eq!(1, calls);
+        assert_eq!(json!({"prop1": "val1", "prop2": "theirs", "prop3": "mine"}).to_string(), saved.jsonify());
+        let doc = database.get_document(String::from("foo")).unwrap();
+        assert_eq!(json!({"prop1": "val1", "prop2": "theirs", "prop3": "mine"}).to_string(), doc.jsonify());
+    }
+
+    #[test]
+    fn save_empty_document_with_conflict_handler() {
+        let database = open_database();
+        let doc = Document::new(String::from("foo"));
+        match database.save_document_with_conflict_handler(doc, |_, _| true) {
+            Err(CouchbaseLiteError::CannotSaveEmptyDocument) => {}
+            other => panic!("expected CannotSaveEmptyDocument, got {:?}", other),
+        }
+        assert_eq!(0, database.count());
+    }
+
+    #[test]
+    fn save_document_with_rejecting_conflict_handler() {
+        let database = open_database();
+        let mine = conflicting_documents(&database);
+        match database.save_document_with_conflict_handler(mine, |_, _| false) {
+            Err(CouchbaseLiteError::Conflict { doc_id }) => assert_eq!("foo", doc_id),
+            other => panic!("expected Conflict, got {:?}", other.map(|doc| doc.jsonify())),
+        }
+        let doc = database.get_document(String::from("foo")).unwrap();
+        assert_eq!(json!({"prop1": "val1", "prop2": "theirs"}).to_string(), doc.jsonify());
+    }
+
+    #[test]
+    fn save_document_without_conflict_skips_handler() {
+        let database = open_database();
+        let mut doc = Document::new(String::from("foo"));
+        doc.set_value(String::from("val1"), String::from("prop1"));
+        let saved = database.save_document_with_conflict_handler(doc, |_, _| panic!("no conflict expected"));
+        assert!(saved.is_ok());
+    }
+
+    #[test]
+    fn update_document_retries_on_conflict() {
+        let database = open_database();
+        let mut doc = Document::new(String::from("counter"));
+        doc.fill(json!({"count": 0}).to_string()).unwrap();
+        database.save_document(doc).unwrap();
+
+        let mut calls = 0;
+        let saved = database
+            .update_document("counter", |doc| {
+                calls += 1;
+                if calls == 1 {
+                    // Simulate a concurrent writer between fetch and save
+                    let mut other = database.get_document(String::from("counter")).unwrap();
+                    other.fill(json!({"count": 10}).to_string()).unwrap();
+                    database.save_document(other).unwrap();
+                }
+                let count: serde_json::Value = serde_json::from_str(&doc.jsonify()).unwrap();
+                doc.fill(json!({"count": count["count"].as_i64().unwrap() + 1}).to_string()).unwrap();
+                Ok(UpdateAction::Save)
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(2, calls);
+        assert_eq!(json!({"count": 11}).to_string(), saved.jsonify());
+    }
+
+    #[test]
+    fn update_document_gives_up_after_max_retries() {
+        let database = open_database();
+        let mut doc = Document::new(String::from("foo"));
+        doc.set_value(String::from("val1"), String::from("prop1"));
+        database.save_document(doc).unwrap();
+
+        let options = UpdateOptions {
+            max_retries: 2,
+            create_if_missing: false,
+        };
+        let mut calls = 0;
+        let result = database.update_document_with("foo", &options, |doc| {
+            calls += 1;
+            let mut other = database.get_document(String::from("foo")).unwrap();
+            other.set_value(format!("other{}", calls), String::from("prop1"));
+            database.save_document(other).unwrap();
+            doc.set_value(String::from("mine"), String::from("prop1"));
+            Ok(UpdateAction::Save)
+        });
+        assert_eq!(3, calls);
+        match result {
+            Err(CouchbaseLiteError::Conflict { doc_id }) => assert_eq!("foo", doc_id),
+            other => panic!("expected Conflict, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn update_missing_document() {
+        let database = open_database();
+        match database.update_document("foo", |_| Ok(UpdateAction::Save)) {
+            Err(CouchbaseLiteError::DocumentNotFound(doc_id)) => assert_eq!("foo", doc_id),
+            other => panic!("expected DocumentNotFound, got {:?}", other),
+        }
+
+        let options = UpdateOptions {
+            create_if_missing: true,
+            ..UpdateOptions::default()
+        };
+        let saved = database
+            .update_document_with("foo", &options, |doc| {
+                doc.set_value(String::from("val1"), String::from("prop1"));
+                Ok(UpdateAction::Save)
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(1, saved.sequence());
+
+        let cancelled = database.update_document("foo", |_| Ok(UpdateAction::Cancel)).unwrap();
+        assert!(cancelled.is_none());
+    }
+
+    #[test]
+    fn transaction_commits_on_success() {
+        let database = open_database();
+        let mut saved_ids = Vec::new();
+        let count = database
+            .transaction(|database| -> Result<u64, CouchbaseLiteError> {
+                for i in 0..10 {
+                    let mut doc = Document::new(format!("id_{}", i));
+                    doc.set_value(format!("Howdy{}!", i), String::from("greeting"));
+                    saved_ids.push(database.save_document(doc)?.id());
+                }
+                Ok(database.count())
+            })
+            .unwrap();
+        assert_eq!(10, count);
+        assert_eq!(10, saved_ids.len());
+        assert_eq!(10, database.count());
+    }
+
+    #[test]
+    fn transaction_rolls_back_on_error() {
+        let database = open_database();
+        let result = database.transaction(|database| {
+            let mut doc = Document::new(String::from("foo"));
+            doc.set_value(String::from("val1"), String::from("prop1"));
+            database.save_document(doc)?;
+            // Saving an empty document fails
+            database.save_document(Document::new(String::from("bar")))
+        });
+        match result {
+            Err(CouchbaseLiteError::CannotSaveEmptyDocument) => {}
+            other => panic!("expected CannotSaveEmptyDocument, got {:?}", other.map(|doc| doc.id())),
+        }
+        assert_eq!(0, database.count());
+        assert!(database.get_document(String::from("foo")).is_none());
+    }
+
+    #[test]
+    fn transaction_rolls_back_on_panic() {
+        let database = open_database();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            database.transaction(|database| -> Result<(), CouchbaseLiteError> {
+                let mut doc = Document::new(String::from("foo"));
+                doc.set_value(String::from("val1"), String::from("prop1"));
+                database.save_document(doc)?;
+                panic!("failure in transaction");
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(0, database.count());
+    }
+
+    #[test]
+    fn nested_transactions_commit_once() {
+        let database = open_database();
+        assert!(!database.in_transaction());
+        database
+            .transaction(|database| -> Result<(), CouchbaseLiteError> {
+                database.transaction(|database| -> Result<(), CouchbaseLiteError> {
+                    assert!(database.in_transaction());
+                    let mut doc = Document::new(String::from("foo"));
+                    doc.set_value(String::from("val1"), String::from("prop1"));
+                    database.save_document(doc)?;
+                    Ok(())
+                })?;
+                // Only the outer transaction commits
+                assert!(database.in_transaction());
+                let mut doc = Document::new(String::from("bar"));
+                doc.set_value(String::from("val1"), String::from("prop1"));
+                database.save_document(doc)?;
+                Ok(())
+            })
+            .unwrap();
+        assert!(!database.in_transaction());
+        assert_eq!(2, database.count());
+    }
+
+    #[test]
+    fn failed_inner_transaction_rolls_back_to_savepoint() {
+        let database = open_database();
+        database
+            .transaction(|database| -> Result<(), CouchbaseLiteError> {
+                let mut doc = Document::new(String::from("foo"));
+                doc.set_value(String::from("val1"), String::from("prop1"));
+                database.save_document(doc)?;
+                let inner = database.transaction(|database| -> Result<(), CouchbaseLiteError> {
+                    let mut doc = Document::new(String::from("bar"));
+                    doc.set_value(String::from("val1"), String::from("prop1"));
+                    database.save_document(doc)?;
+                    database.save_document(Document::new(String::from("baz")))?;
+                    Ok(())
+                });
+                assert!(matches!(inner, Err(CouchbaseLiteError::CannotSaveEmptyDocument)));
+                assert!(database.in_transaction());
+                assert!(database.get_document(String::from("bar")).is_none());
+                Ok(())
+            })
+            .unwrap();
+        assert!(!database.in_transaction());
+        assert_eq!(1, database.count());
+        assert!(database.get_document(String::from("foo")).is_some());
+    }
+
+    #[test]
+    fn transaction_restores_changed_documents() {
+        let database = open_database();
+        for doc_id in &["foo", "bar"] {
+            let mut doc = Document::new(doc_id.to_string());
+            doc.set_value(String::from("val1"), String::from("prop1"));
+            database.save_document(doc).unwrap();
+        }
+        let expiration = UNIX_EPOCH + Duration::from_secs(4_102_444_800); // 2100-01-01
+        database.set_document_expiration("foo", Some(expiration)).unwrap();
+
+        let result = database.transaction(|database| -> Result<(), CouchbaseLiteError> {
+            let mut foo = database.get_document(String::from("foo")).unwrap();
+            foo.set_value(String::from("val2"), String::from("prop1"));
+            database.save_document(foo)?;
+            database.set_document_expiration("foo", None)?;
+            database.delete_document_by_id("bar", Concurrency::LastWriteWins)?;
+            let mut baz = Document::new(String::from("baz"));
+            baz.set_value(String::from("val1"), String::from("prop1"));
+            database.save_document(baz)?;
+            Err(CouchbaseLiteError::DocumentNotFound(String::from("qux")))
+        });
+        assert!(matches!(result, Err(CouchbaseLiteError::DocumentNotFound(_))));
+        assert_eq!(2, database.count());
+        assert_eq!("{\"prop1\":\"val1\"}", database.get_document(String::from("foo")).unwrap().jsonify());
+        assert_eq!(Some(expiration), database.document_expiration("foo").unwrap());
+        assert_eq!("{\"prop1\":\"val1\"}", database.get_document(String::from("bar")).unwrap().jsonify());
+        assert!(database.get_document(String::from("baz")).is_none());
+    }
+
+    #[test]
+    fn nested_batches() {
+        let database = open_database();
+        database
+            .in_batch(&|| {
+                database
+                    .in_batch(&|| {
+                        let mut doc = Document::new(String::from("foo"));
+                        doc.set_value(String::from("val1"), String::from("prop1"));
+                        database.save_document(doc).unwrap();
+                    })
+                    .unwrap();
+                assert!(database.in_transaction());
+            })
+            .unwrap();
+        assert!(!database.in_transaction());
+        assert_eq!(1, database.count());
+    }
+
+    #[test]
+    fn failed_inner_batch_rolls_back_to_savepoint() {
+        let database = open_database();
+        database
+            .in_batch_with_result(&|| {
+                let mut doc = Document::new(String::from("foo"));
+                doc.set_value(String::from("val1"), String::from("prop1"));
+                database.save_document(doc)?;
+                let inner = database.in_batch_with_result(&|| {
+                    let mut doc = Document::new(String::from("bar"));
+                    doc.set_value(String::from("val1"), String::from("prop1"));
+                    database.save_document(doc)?;
+                    database.save_document(Document::new(String::from("empty")))
+                });
+                assert!(matches!(inner, Err(CouchbaseLiteError::CannotSaveEmptyDocument)));
+                let mut doc = Document::new(String::from("baz"));
+                doc.set_value(String::from("val1"), String::from("prop1"));
+                database.save_document(doc)
+            })
+            .unwrap();
+        assert_eq!(2, database.count());
+        assert!(database.get_document(String::from("foo")).is_some());
+        assert!(database.get_document(String::from("bar")).is_none());
+        assert!(database.get_document(String::from("baz")).is_some());
+    }
+
+    #[test]
+    fn panic_in_batch_resets_nesting() {
+        let database = open_database();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            database
+                .in_batch(&|| {
+                    let mut doc = Document::new(String::from("foo"));
+                    doc.set_value(String::from("val1"), String::from("prop1"));
+                    database.save_document(doc).unwrap();
+                    panic!("failure in batch");
+                })
+                .unwrap();
+        }));
+        assert!(result.is_err());
+        assert!(!database.in_transaction());
+        // Batches commit whatever was written before the panic
+        assert_eq!(1, database.count());
+    }
+
+    #[test]
+    fn clone_database() {
+        let database = open_database();
+        let clone = database.clone();
+        drop(database);
+        let mut doc = Document::new(String::from("foo"));
+        doc.set_value(String::from("val1"), String::from("prop1"));
+        clone.save_document(doc).unwrap();
+        assert_eq!(1, clone.count());
+    }
+
+    #[test]
+    fn share_database_across_threads() {
+        let database = open_database();
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let database = database.clone();
+                thread::spawn(move || {
+                    for i in 0..10 {
+                        database
+                            .in_batch_with_result(&|| {
+                                assert!(database.in_transaction());
+                                for j in 0..10 {
+                                    let mut doc = Document::new(format!("id_{}_{}_{}", t, i, j));
+                                    doc.set_value(format!("Howdy{}!", j), String::from("greeting"));
+                                    database.save_document(doc)?;
+                                }
+                                Ok(())
+                            })
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(400, database.count());
+    }
+
+    #[test]
+    fn batch_is_owned_by_its_thread() {
+        let database = open_database();
+        let (sender, receiver) = mpsc::channel();
+        database
+            .in_batch_with_result(&|| {
+                let other = database.clone();
+                let sender = sender.clone();
+                let handle = thread::spawn(move || {
+                    assert!(!other.in_transaction());
+                    // Waits until the outer batch is committed
+                    other
+                        .in_batch_with_result(&|| {
+                            let mut doc = Document::new(String::from("bar"));
+                            doc.set_value(String::from("val1"), String::from("prop1"));
+                            other.save_document(doc)
+                        })
+                        .unwrap();
+                    sender.send(other.count()).unwrap();
+                });
+                thread::sleep(Duration::from_millis(200));
+                let mut doc = Document::new(String::from("foo"));
+                doc.set_value(String::from("val1"), String::from("prop1"));
+                database.save_document(doc)?;
+                assert!(receiver.try_recv().is_err());
+                drop(handle);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(2, receiver.recv_timeout(Duration::from_secs(5)).unwrap());
+    }
+
+    #[test]
+    fn write_waits_for_batch_of_other_thread() {
+        let database = open_database();
+        let (sender, receiver) = mpsc::channel();
+        database
+            .in_batch(&|| {
+                let mut doc = Document::new(String::from("foo"));
+                doc.set_value(String::from("val1"), String::from("prop1"));
+                database.save_document(doc).unwrap();
+                let other = database.clone();
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    // Waits until the batch is committed instead of joining it
+                    let mut doc = Document::new(String::from("bar"));
+                    doc.set_value(String::from("val1"), String::from("prop1"));
+                    other.save_document(doc).unwrap();
+                    sender.send(()).unwrap();
+                });
+                thread::sleep(Duration::from_millis(200));
+                assert!(receiver.try_recv().is_err());
+                assert_eq!(1, database.count());
+            })
+            .unwrap();
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(2, database.count());
+    }
+
+    #[test]
+    fn document_outlives_database_handle() {
+        let database = open_database();
+        let mut doc = database.create_document(String::from("foo"));
+        doc.set_value(String::from("val1"), String::from("prop1"));
+        let mut saved = database.save_document(doc).unwrap();
+        drop(database);
+        assert!(saved.database().unwrap().is_open());
+        assert_eq!("{\"prop1\":\"val1\"}", saved.jsonify());
+        saved.set_value(String::from("val2"), String::from("prop1"));
+        let database = saved.database().unwrap().clone();
+        database.save_document(saved).unwrap();
+        assert_eq!(1, database.count());
+    }
+
+    #[test]
+    fn use_after_close() {
+        let database = open_database();
+        let mut doc = database.create_document(String::from("foo"));
+        doc.set_value(String::from("val1"), String::from("prop1"));
+        let saved = database.save_document(doc).unwrap();
+        database.clone().close().unwrap();
+        assert!(!database.is_open());
+        assert!(database.get_document(String::from("foo")).is_none());
+        assert!(matches!(database.purge_document(&saved), Err(CouchbaseLiteError::DatabaseNotOpen)));
+        assert!(matches!(database.save_document(saved), Err(CouchbaseLiteError::DatabaseNotOpen)));
+        assert!(matches!(database.new_query(String::from("SELECT _id")), Err(CouchbaseLiteError::DatabaseNotOpen)));
+        assert!(matches!(database.in_batch(&|| {}), Err(CouchbaseLiteError::DatabaseNotOpen)));
+        assert!(matches!(database.add_change_listener(|_, _| {}), Err(CouchbaseLiteError::DatabaseNotOpen)));
+        assert!(matches!(database.buffer_notifications(|_| {}), Err(CouchbaseLiteError::DatabaseNotOpen)));
+        assert!(matches!(database.send_pending_notifications(), Err(CouchbaseLiteError::DatabaseNotOpen)));
+        assert_eq!(0, database.count());
+        assert_eq!("testdb", database.get_name());
+        assert!(matches!(database.close(), Err(CouchbaseLiteError::DatabaseNotOpen)));
+        assert!(matches!(database.delete(), Err(CouchbaseLiteError::DatabaseNotOpen)));
+    }
+
+    #[test]
+    fn reject_nul_in_strings() {
+        let database = open_database();
+        assert!(matches!(Document::try_new("foo\0bar"), Err(CouchbaseLiteError::InvalidString(_))));
+        assert!(matches!(database.try_create_document("foo\0bar"), Err(CouchbaseLiteError::InvalidString(_))));
+        assert!(database.get_document(String::from("foo\0bar")).is_none());
+        assert!(matches!(database.delete_document_by_id("foo\0bar", Concurrency::LastWriteWins), Err(CouchbaseLiteError::InvalidString(_))));
+        assert!(matches!(database.new_query(String::from("SELECT\0")), Err(CouchbaseLiteError::InvalidString(_))));
+        assert!(!Database::exists("test\0db", "/tmp"));
+    }
+
+    #[test]
+    fn close_while_writing() {
+        let database = open_database();
+        let writer = database.clone();
+        let handle = thread::spawn(move || {
+            for i in 0.. {
+                let mut doc = Document::new(format!("id_{}", i));
+                doc.set_value(format!("Howdy{}!", i), String::from("greeting"));
+                match writer.save_document(doc) {
+                    Ok(_) => {}
+                    Err(CouchbaseLiteError::DatabaseNotOpen) => return i,
+                    Err(error) => panic!("unexpected error {}", error),
+                }
+            }
+            unreachable!()
+        });
+        thread::sleep(Duration::from_millis(100));
+        database.close().unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn listener_writes_during_batch_commit() {
+        let database = open_database();
+        let _token = database
+            .add_document_change_listener("foo", |database, _| {
+                if database.get_document(String::from("bar")).is_none() {
+                    let mut doc = Document::new(String::from("bar"));
+                    doc.set_value(String::from("val1"), String::from("prop1"));
+                    database.save_document(doc).unwrap();
+                }
+            })
+            .unwrap();
+        database
+            .in_batch_with_result(&|| {
+                let mut doc = Document::new(String::from("foo"));
+                doc.set_value(String::from("val1"), String::from("prop1"));
+                database.save_document(doc)
+            })
+            .unwrap();
+        assert_eq!(2, database.count());
+    }
+
+    #[test]
+    fn listener_uses_database_while_it_is_closed() {
+        let database = open_database();
+        database.buffer_notifications(|_| {}).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let _token = database
+            .add_change_listener(move |database, _| {
+                let closer = database.clone();
+                thread::spawn(move || closer.close().unwrap());
+                // The closing thread waits until the listener returns
+                thread::sleep(Duration::from_millis(200));
+                sender.send(database.count()).unwrap();
+            })
+            .unwrap();
+        let mut doc = Document::new(String::from("foo"));
+        doc.set_value(String::from("val1"), String::from("prop1"));
+        database.save_document(doc).unwrap();
+        database.send_pending_notifications().unwrap();
+        assert_eq!(1, receiver.recv_timeout(Duration::from_secs(5)).unwrap());
+        for _ in 0..50 {
+            if !database.is_open() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert!(!database.is_open());
+    }
+
+    /// Returns the resident set size of the process, in bytes.
+    #[cfg(target_os = "linux")]
+    fn resident_memory() -> u64 {
+        let statm = fs::read_to_string("/proc/self/statm").unwrap();
+        let pages: u64 = statm.split_whitespace().nth(1).unwrap().parse().unwrap();
+        pages * 4096
+    }
+
+    #[test]
+    #[ignore]
+    #[cfg(target_os = "linux")]
+    fn save_million_documents_without_leaking() {
+        let database = open_database();
+        let save = |from: usize, to: usize| {
+            for batch in (from..to).step_by(1000) {
+                database
+                    .in_batch_with_result(&|| {
+                        for i in batch..batch + 1000 {
+                            let mut doc = database.create_document(format!("doc_{}", i));
+                            doc.set_value(format!("Howdy{}!", i), String::from("greeting"));
+                            let saved = database.save_document(doc)?;
+                            assert_eq!(format!("{{\"greeting\":\"Howdy{}!\"}}", i), saved.jsonify());
+                        }
+                        Ok(())
+                    })
+                    .unwrap();
+            }
+        };
+        // Let the caches fill up first
+        save(0, 100_000);
+        let before = resident_memory();
+        save(100_000, 1_000_000);
+        let after = resident_memory();
+        assert_eq!(1_000_000, database.count());
+        let growth = after.saturating_sub(before);
+        assert!(growth < 32 * 1024 * 1024, "memory grew by {} bytes", growth);
+    }
 }
@@ -1,72 +1,288 @@
+use crate::blob::Blob;
+use crate::database::{Database, OpenGuard};
 use crate::errors::init_error;
 use crate::errors::CouchbaseLiteError;
-use crate::to_ptr;
-use crate::to_string;
+use crate::errors::NativeError;
+use crate::serialization::{from_value, to_array_item, to_mutable_dict};
+use crate::fleece::{Array, Dict, MutableArrayRef, MutableDictRef, Value};
+use crate::keypath::{self, KeyPath};
+use crate::{slice_result_to_string, to_bytes, to_cstring, to_slice, to_string};
 use core::mem;
 use ffi;
-use std::os::raw::c_void;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::mem::ManuallyDrop;
 
 
-// TODO add generic T: Serialize
 //TODO implement Deref and call unsafe { ffi:CBRelease(saved) };
+/// A document, wrapping `CBLDocument`.
+///
+/// A document fetched from or saved to a database holds a handle on it, so the database can't be
+/// released while the document is alive. Using the document with a database that was closed in the
+/// meantime returns `DatabaseNotOpen`.
 #[derive(Debug)]
 pub struct Document /*<T>*/ {
     pub doc: *mut ffi::CBLDocument,
-    db: Option<*mut ffi::CBLDatabase>,
+    database: Option<Database>,
     //properties: T
 }
 
 impl Document {
+    /// Creates a new, empty document in memory.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` contains a NUL byte, see `try_new`.
     pub fn new(id: String) -> Self {
-        let doc = unsafe { ffi::CBLDocument_New(to_ptr(id)) };
-        Document { doc, db: None }
+        Document::try_new(&id).expect("document IDs can't contain NUL bytes")
+    }
+
+    /// Creates a new, empty document in memory, failing with `InvalidString` if `id` contains a NUL byte.
+    pub fn try_new(id: &str) -> Result<Self, CouchbaseLiteError> {
+        let id = to_cstring(id)?;
+        let doc = unsafe { ffi::CBLDocument_New(id.as_ptr()) };
+        Ok(Document { doc, database: None })
+    }
+
+    /// Takes ownership of one reference on `doc`, which must belong to `database`.
+    pub fn from_raw(database: &Database, doc: *mut ffi::CBLDocument) -> Self {
+        Document {
+            database: Some(database.clone()),
+            doc,
+        }
+    }
+
+    /// Wraps a document owned by the C library for the duration of a callback.
+    pub(crate) fn borrowed(doc: *mut ffi::CBLDocument) -> ManuallyDrop<Self> {
+        ManuallyDrop::new(Document { doc, database: None })
+    }
+
+    /// Returns the database the document was fetched from or saved to, if any.
+    pub fn database(&self) -> Option<&Database> {
+        self.database.as_ref()
     }
 
-    pub fn from_raw(db: *mut ffi::CBLDatabase, doc: *mut ffi::CBLDocument) -> Self {
-        Document { db: Some(db), doc }
+    /// Fails with `DatabaseNotOpen` if the document's database was closed. Otherwise the returned
+    /// guard (if the document belongs to a database) keeps it open until dropped.
+    pub(crate) fn check_open(&self) -> Result<Option<OpenGuard<'_>>, CouchbaseLiteError> {
+        match &self.database {
+            Some(database) => database.check_open().map(Some),
+            None => Ok(None),
+        }
     }
 
-    // Returns the document id.
+    // Returns the document id. Invalid UTF-8 is replaced with U+FFFD, see `id_bytes`.
     pub fn id(&self) -> String {
         let doc_id = unsafe { ffi::CBLDocument_ID(self.doc) };
         to_string(doc_id)
     }
 
+    /// Returns the raw bytes of the document id, which isn't necessarily valid UTF-8.
+    pub fn id_bytes(&self) -> Vec<u8> {
+        let doc_id = unsafe { ffi::CBLDocument_ID(self.doc) };
+        to_bytes(doc_id).to_vec()
+    }
+
+    pub(crate) fn native_id(&self) -> &CStr {
+        unsafe { CStr::from_ptr(ffi::CBLDocument_ID(self.doc)) }
+    }
+
     /// Sets a mutable document's properties from a JSON string.
     ///
     /// Returns true iff it was successful.
-    pub fn fill(&self, json: String) -> Result<bool, CouchbaseLiteError> {
+    pub fn fill(&mut self, json: String) -> Result<bool, CouchbaseLiteError> {
         let mut error = init_error();
-        let json_string = to_ptr(json);
-        let status = unsafe { ffi::CBLDocument_SetPropertiesAsJSON(self.doc, json_string, &mut error) };
-        println!("jsonify {:?} - error: {:?}", status, error);
+        let json_string = to_cstring(&json)?;
+        let status = unsafe { ffi::CBLDocument_SetPropertiesAsJSON(self.doc, json_string.as_ptr(), &mut error) };
         if error.code == 0 {
             Ok(status)
         } else {
-            Err(CouchbaseLiteError::CannotFillDocumentFromJson(error))
+            Err(CouchbaseLiteError::CannotFillDocumentFromJson(NativeError::for_document(error, self.id())))
+        }
+    }
+
+    /// Replaces a mutable document's properties with `value`, which must serialize to a map.
+    ///
+    /// Unlike `fill`, the value is converted to Fleece directly, without going through JSON.
+    pub fn set_properties<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), CouchbaseLiteError> {
+        let properties = to_mutable_dict(value).map_err(CouchbaseLiteError::CannotSerializeDocument)?;
+        unsafe {
+            ffi::CBLDocument_SetProperties(self.doc, properties);
+            ffi::FLValue_Release(properties as ffi::FLValue);
         }
+        Ok(())
+    }
+
+    /// Deserializes the document's properties, reading the Fleece values directly.
+    pub fn properties<T: DeserializeOwned>(&self) -> Result<T, CouchbaseLiteError> {
+        let properties = unsafe { ffi::CBLDocument_Properties(self.doc) };
+        from_value(properties as ffi::FLValue).map_err(|error| CouchbaseLiteError::CannotDeserializeDocument { doc_id: self.id(), error })
     }
 
     pub fn jsonify(&self) -> String {
-        let json: *mut ::std::os::raw::c_char = unsafe { ffi::CBLDocument_PropertiesAsJSON(self.doc) };
-        to_string(json)
+        // Same as CBLDocument_PropertiesAsJSON, without the malloc'ed copy
+        let json = unsafe { ffi::FLValue_ToJSON(ffi::CBLDocument_Properties(self.doc) as ffi::FLValue) };
+        slice_result_to_string(json)
     }
 
-    pub fn set_value(&self, value_string: String, for_key: String) {
+    pub fn set_value(&mut self, value_string: String, for_key: String) {
         unsafe {
             let properties = ffi::CBLDocument_MutableProperties(self.doc);
-            let key_slice = ffi::FLString {
-                buf: to_ptr(for_key.clone()) as *const c_void,
-                size: for_key.len(),
-            };
             //let fl_value = ffi::FLDict_Get(properties, key);
             //let fl_string = ffi::FLValue_AsString(fl_value);
-            let fl_slot = ffi::FLMutableDict_Set(properties, key_slice);
-            let value_slice = ffi::FLString {
-                buf: to_ptr(value_string.clone()) as *const c_void,
-                size: value_string.len(),
-            };
-            ffi::FLSlot_SetString(fl_slot, value_slice);
+            // Fleece copies both slices
+            let fl_slot = ffi::FLMutableDict_Set(properties, to_slice(&for_key));
+            ffi::FLSlot_SetString(fl_slot, to_slice(&value_string));
+        }
+    }
+
+    /// Returns the document's properties as a Fleece dict.
+    pub fn properties_dict(&self) -> &Dict {
+        unsafe { &*(ffi::CBLDocument_Properties(self.doc) as *const Dict) }
+    }
+
+    /// Returns the value of a property, or the undefined value if it is missing.
+    pub fn get(&self, key: &str) -> &Value {
+        &self.properties_dict()[key]
+    }
+
+    /// Returns a string property, or `None` if it is missing, not a string or not valid UTF-8.
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key).as_str()
+    }
+
+    pub fn get_i64(&self, key: &str) -> Option<i64> {
+        self.get(key).as_i64()
+    }
+
+    pub fn get_f64(&self, key: &str) -> Option<f64> {
+        self.get(key).as_f64()
+    }
+
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        self.get(key).as_bool()
+    }
+
+    pub fn get_data(&self, key: &str) -> Option<&[u8]> {
+        self.get(key).as_data()
+    }
+
+    pub fn get_dict(&self, key: &str) -> Option<&Dict> {
+        self.get(key).as_dict()
+    }
+
+    pub fn get_array(&self, key: &str) -> Option<&Array> {
+        self.get(key).as_array()
+    }
+
+    /// Returns a blob property, or `None` if the property isn't a blob dictionary.
+    pub fn get_blob(&self, key: &str) -> Option<Blob<'_>> {
+        self.get_dict(key).and_then(|dict| Blob::from_dict(self, dict.as_raw()))
+    }
+
+    /// Returns true iff the property is null. A missing property isn't null, see `contains_key`.
+    pub fn is_null(&self, key: &str) -> bool {
+        self.get(key).is_null()
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.properties_dict().contains_key(key)
+    }
+
+    /// Iterates over the property names, in Fleece order.
+    pub fn keys(&self) -> impl Iterator<Item = Cow<'_, str>> {
+        self.properties_dict().keys()
+    }
+
+    fn mutable_properties(&mut self) -> MutableDictRef<'_> {
+        unsafe { MutableDictRef::from_raw(ffi::CBLDocument_MutableProperties(self.doc)) }.expect("document without properties")
+    }
+
+    pub fn set_null(&mut self, key: &str) {
+        self.mutable_properties().set(key).set_null();
+    }
+
+    pub fn set_bool(&mut self, key: &str, value: bool) {
+        self.mutable_properties().set(key).set_bool(value);
+    }
+
+    pub fn set_i64(&mut self, key: &str, value: i64) {
+        self.mutable_properties().set(key).set_i64(value);
+    }
+
+    pub fn set_u64(&mut self, key: &str, value: u64) {
+        self.mutable_properties().set(key).set_u64(value);
+    }
+
+    pub fn set_f64(&mut self, key: &str, value: f64) {
+        self.mutable_properties().set(key).set_f64(value);
+    }
+
+    pub fn set_str(&mut self, key: &str, value: &str) {
+        self.mutable_properties().set(key).set_str(value);
+    }
+
+    pub fn set_data(&mut self, key: &str, value: &[u8]) {
+        self.mutable_properties().set(key).set_data(value);
+    }
+
+    /// Stores a copy of a dict, so later changes to a `MutableDict` don't show up in the document.
+    pub fn set_dict(&mut self, key: &str, value: &Dict) {
+        self.mutable_properties().set(key).set_value(value.as_value());
+    }
+
+    /// Stores a copy of an array, like `set_dict`.
+    pub fn set_array(&mut self, key: &str, value: &Array) {
+        self.mutable_properties().set(key).set_value(value.as_value());
+    }
+
+    /// Returns a dict property to be modified in place, or `None` if the property isn't a dict.
+    ///
+    /// The dict borrows the document, so it can't be used once the document is changed otherwise or dropped.
+    pub fn get_mutable_dict(&mut self, key: &str) -> Option<MutableDictRef<'_>> {
+        let properties = unsafe { ffi::CBLDocument_MutableProperties(self.doc) };
+        unsafe { MutableDictRef::from_raw(ffi::FLMutableDict_GetMutableDict(properties, to_slice(key))) }
+    }
+
+    /// Returns an array property to be modified in place, or `None` if the property isn't an array.
+    ///
+    /// The array borrows the document, like the dict of `get_mutable_dict`.
+    pub fn get_mutable_array(&mut self, key: &str) -> Option<MutableArrayRef<'_>> {
+        let properties = unsafe { ffi::CBLDocument_MutableProperties(self.doc) };
+        unsafe { MutableArrayRef::from_raw(ffi::FLMutableDict_GetMutableArray(properties, to_slice(key))) }
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.mutable_properties().remove(key);
+    }
+
+    /// Returns the value at a key path in N1QL syntax, e.g. `items[3].sku` (see `KeyPath`), or the
+    /// undefined value if a key or index on the way is missing.
+    pub fn get_path(&self, path: &str) -> Result<&Value, CouchbaseLiteError> {
+        let path = KeyPath::parse(path).map_err(CouchbaseLiteError::InvalidKeyPath)?;
+        keypath::get(self.properties_dict(), &path)
+    }
+
+    /// Stores `value` at a key path in N1QL syntax, creating the missing dicts and arrays on the way.
+    ///
+    /// Fails with `KeyPathTypeMismatch` if the path goes through a value that isn't a collection of
+    /// the right type, or with `KeyPathIndexOutOfBounds` if a negative index goes past the start of
+    /// an array, or an index goes more than 1024 values past its end.
+    pub fn set_path<T: Serialize + ?Sized>(&mut self, path: &str, value: &T) -> Result<(), CouchbaseLiteError> {
+        let path = KeyPath::parse(path).map_err(CouchbaseLiteError::InvalidKeyPath)?;
+        let value = to_array_item(value).map_err(CouchbaseLiteError::CannotSerializeDocument)?;
+        keypath::set(self.mutable_properties(), &path, &value[0])
+    }
+
+    /// Returns the revision ID of the document, or `None` if it was never saved.
+    pub fn revision_id(&self) -> Option<String> {
+        let revision_id = unsafe { ffi::CBLDocument_RevisionID(self.doc) };
+        if revision_id.is_null() {
+            None
+        } else {
+            Some(to_string(revision_id))
         }
     }
 
@@ -83,8 +299,11 @@ impl Drop for Document {
 
 #[cfg(test)]
 mod tests {
+    use crate::CouchbaseLiteError;
     use crate::Document;
+    use crate::{MutableArray, MutableDict, ValueType};
     use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
 
     #[test]
     fn new_document() {
@@ -101,7 +320,7 @@ mod tests {
     #[test]
     fn add_new_property_in_document() {
         let doc_id = String::from("foo");
-        let doc = Document::new(doc_id);
+        let mut doc = Document::new(doc_id);
         doc.set_value(String::from("val1"), String::from("prop1"));
         assert_eq!("{\"prop1\":\"val1\"}", doc.jsonify());
 
@@ -113,7 +332,7 @@ mod tests {
     #[test]
     fn fill_document_from_json_string() {
         let doc_id = String::from("foo");
-        let doc = Document::new(doc_id);
+        let mut doc = Document::new(doc_id);
         let status = doc.fill(String::from("{\"prop1\":\"val1\",\"prop2\":\"val2\"}"));
         assert_eq!(true, status.is_ok());
         let status = status.unwrap();
@@ -133,7 +352,7 @@ mod tests {
             last_name: "Bomb".to_string(),
         };
         let doc_id = String::from("foo");
-        let doc = Document::new(doc_id);
+        let mut doc = Document::new(doc_id);
         let status = doc.fill(serde_json::to_string_pretty(&person).unwrap());
         assert_eq!(true, status.is_ok());
         let status = status.unwrap();
@@ -141,4 +360,172 @@ mod tests {
         assert_eq!("{\"first_name\":\"James\",\"last_name\":\"Bomb\"}", doc.jsonify());
     }
 
+    #[test]
+    fn properties_round_trip() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        pub enum Shape {
+            Point,
+            Circle(f64),
+            Segment(i32, i32),
+            Rectangle { width: u32, height: u32 },
+        }
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        pub struct Address {
+            pub city: String,
+            pub zip: Option<String>,
+        }
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        pub struct Person {
+            pub first_name: String,
+            pub age: u8,
+            pub balance: i64,
+            pub ratio: f64,
+            pub active: bool,
+            pub initial: char,
+            pub address: Address,
+            pub tags: Vec<String>,
+            pub scores: BTreeMap<u32, i32>,
+            pub shapes: Vec<Shape>,
+            pub nothing: (),
+        }
+        let person = Person {
+            first_name: "James".to_string(),
+            age: 42,
+            balance: -1_000_000_000_000,
+            ratio: 0.5,
+            active: true,
+            initial: 'J',
+            address: Address {
+                city: "London".to_string(),
+                zip: None,
+            },
+            tags: vec!["agent".to_string(), "007".to_string()],
+            scores: vec![(1, -1), (2, 2)].into_iter().collect(),
+            shapes: vec![
+                Shape::Point,
+                Shape::Circle(1.5),
+                Shape::Segment(1, 2),
+                Shape::Rectangle { width: 3, height: 4 },
+            ],
+            nothing: (),
+        };
+        let mut doc = Document::new(String::from("foo"));
+        doc.set_properties(&person).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&doc.jsonify()).unwrap();
+        assert_eq!(serde_json::to_value(&person).unwrap(), json);
+        assert_eq!(person, doc.properties::<Person>().unwrap());
+    }
+
+    #[test]
+    fn properties_errors() {
+        let mut doc = Document::new(String::from("foo"));
+        match doc.set_properties(&vec![1, 2]) {
+            Err(CouchbaseLiteError::CannotSerializeDocument(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        doc.set_value(String::from("val1"), String::from("prop1"));
+        match doc.properties::<BTreeMap<String, u32>>() {
+            Err(CouchbaseLiteError::CannotDeserializeDocument { doc_id, .. }) => assert_eq!("foo", doc_id),
+            other => panic!("unexpected result {:?}", other),
+        }
+        let properties: BTreeMap<String, String> = doc.properties().unwrap();
+        assert_eq!(Some("val1"), properties.get("prop1").map(String::as_str));
+    }
+
+    #[test]
+    fn typed_properties() {
+        let mut doc = Document::new(String::from("foo"));
+        let mut address = MutableDict::new();
+        address.set("city").set_str("London");
+        let mut tags = MutableArray::new();
+        tags.append().set_str("agent");
+        doc.set_str("name", "James");
+        doc.set_i64("age", 42);
+        doc.set_f64("ratio", 0.5);
+        doc.set_bool("active", true);
+        doc.set_null("nothing");
+        doc.set_data("bytes", &[1, 2]);
+        doc.set_dict("address", &address);
+        doc.set_array("tags", &tags);
+
+        assert_eq!(Some("James"), doc.get_str("name"));
+        assert_eq!(None, doc.get_str("age"));
+        assert_eq!(Some(42), doc.get_i64("age"));
+        assert_eq!(Some(0.5), doc.get_f64("ratio"));
+        assert_eq!(Some(true), doc.get_bool("active"));
+        assert!(doc.is_null("nothing"));
+        assert!(!doc.is_null("missing"));
+        assert_eq!(Some(&[1u8, 2][..]), doc.get_data("bytes"));
+        assert_eq!(Some("London"), doc.get_dict("address").and_then(|address| address["city"].as_str()));
+        assert_eq!(1, doc.get_array("tags").map_or(0, |tags| tags.len()));
+        assert!(doc.get_blob("address").is_none());
+        assert!(doc.get("missing").is_undefined());
+
+        // The document holds copies of the collections
+        address.set("city").set_str("Paris");
+        tags.append().set_str("spy");
+        assert_eq!(Some("London"), doc.get("address")["city"].as_str());
+        assert_eq!(1, doc.get_array("tags").map_or(0, |tags| tags.len()));
+
+        doc.get_mutable_dict("address").unwrap().set("zip").set_str("SW1");
+        assert_eq!(Some("SW1"), doc.get("address")["zip"].as_str());
+        assert!(address["zip"].is_undefined());
+        doc.get_mutable_array("tags").unwrap().append().set_str("007");
+        assert_eq!(Some("007"), doc.get("tags")[1].as_str());
+
+        assert!(doc.contains_key("nothing"));
+        doc.remove("nothing");
+        assert!(!doc.contains_key("nothing"));
+        let keys: Vec<String> = doc.keys().map(|key| key.into_owned()).collect();
+        assert_eq!(vec!["active", "address", "age", "bytes", "name", "ratio", "tags"], keys);
+    }
+
+    #[test]
+    fn key_paths() {
+        let mut doc = Document::new(String::from("foo"));
+        doc.fill(String::from(r#"{"name":"James","items":[{"sku":"a1"}]}"#)).unwrap();
+        doc.set_path("address.geo.lat", &51.5).unwrap();
+        doc.set_path("items[2].sku", "c3").unwrap();
+        doc.set_path("items[-3].qty", &2).unwrap();
+        doc.set_path("`odd.key`[0]", &true).unwrap();
+
+        assert_eq!(Some(51.5), doc.get_path("address.geo.lat").unwrap().as_f64());
+        assert_eq!(Some("c3"), doc.get_path("items[-1].sku").unwrap().as_str());
+        assert_eq!(Some(2), doc.get_path("items[0].qty").unwrap().as_i64());
+        assert!(doc.get_path("items[1]").unwrap().is_null());
+        assert!(doc.get_path("items[5].sku").unwrap().is_undefined());
+        assert!(doc.get_path("missing.deeper[0]").unwrap().is_undefined());
+        assert_eq!(Some(true), doc.get_path("`odd.key`[0]").unwrap().as_bool());
+
+        match doc.set_path("name.first", "James") {
+            Err(CouchbaseLiteError::KeyPathTypeMismatch { path, expected, found }) => {
+                assert_eq!(("name", ValueType::Dict, ValueType::String), (path.as_str(), expected, found))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        match doc.get_path("items.sku") {
+            Err(CouchbaseLiteError::KeyPathTypeMismatch { path, .. }) => assert_eq!("items", path),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match doc.set_path("items[-4]", &0) {
+            Err(CouchbaseLiteError::KeyPathIndexOutOfBounds { path, len, .. }) => assert_eq!(("items", 3), (path.as_str(), len)),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match doc.set_path("items[1000000000]", &0) {
+            Err(CouchbaseLiteError::KeyPathIndexOutOfBounds { path, index, len }) => {
+                assert_eq!(("items", 1_000_000_000, 3), (path.as_str(), index, len))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        doc.set_path("items[1027]", &0).unwrap();
+        assert_eq!(1028, doc.get_array("items").map_or(0, |items| items.len()));
+        match doc.set_path("items[4294967296]", &0) {
+            Err(CouchbaseLiteError::InvalidKeyPath(error)) => assert_eq!("array index out of range", error.message),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match doc.get_path("items[") {
+            Err(CouchbaseLiteError::InvalidKeyPath(error)) => assert_eq!(6, error.position),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
 }
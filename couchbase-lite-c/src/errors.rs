@@ -1,21 +1,325 @@
+use crate::config::DatabaseConfig;
+use crate::keypath::KeyPathError;
+use crate::serialization::SerializationError;
+use crate::ValueType;
+use crate::to_string;
 use ffi;
 
+use std::error::Error;
+use std::ffi::NulError;
+use std::fmt;
+use std::io;
+use std::os::raw::{c_char, c_void};
+
+extern "C" {
+    // CBLError_Message returns a string allocated with malloc
+    fn free(pointer: *mut c_void);
+}
+
 #[derive(Debug)]
 pub enum CouchbaseLiteError {
-    CannotOpenDatabase(ffi::CBLError),
-    CannotCloseDatabase(ffi::CBLError),
-    CannotSaveDocument(ffi::CBLError),
+    CannotOpenDatabase(NativeError),
+    DatabaseNotFound(NativeError),
+    DatabaseLocked(NativeError),
+    DatabaseCorrupt(NativeError),
+    WrongEncryptionKey(NativeError),
+    CannotDeriveEncryptionKey,
+    CannotChangeEncryptionKey(NativeError),
+    CannotCloseDatabase(NativeError),
+    DatabaseNotOpen,
+    CannotSaveDocument(NativeError),
     CannotSaveEmptyDocument,
-    CannotDeleteDocument(ffi::CBLError),
-    CannotCreateNewQuery(ffi::CBLError),
-    CannotFillDocumentFromJson(ffi::CBLError),
-    CannotCreateNewReplicator(ffi::CBLError),
-    CannotExecuteQuery(ffi::CBLError),
-    CannotCreateIndex(ffi::CBLError),
-    ErrorInBatch(ffi::CBLError),
-    CannotDeleteDatabase(ffi::CBLError),
+    Conflict { doc_id: String },
+    CannotDeleteDocument(NativeError),
+    CannotPurgeDocument(NativeError),
+    DocumentNotFound(String),
+    DocumentFromOtherDatabase { doc_id: String },
+    CannotSetDocumentExpiration(NativeError),
+    CannotGetDocumentExpiration(NativeError),
+    CannotCreateNewQuery(NativeError),
+    CannotFillDocumentFromJson(NativeError),
+    CannotCreateNewReplicator(NativeError),
+    CannotExecuteQuery(NativeError),
+    CannotCreateIndex(NativeError),
+    ErrorInBatch(NativeError),
+    ErrorInTransaction(NativeError),
+    CannotDeleteDatabase(NativeError),
+    CannotCopyDatabase(NativeError),
+    CannotPerformMaintenance(NativeError),
+    CannotReadDatabaseSize(io::Error),
+    CannotSerializeDocument(SerializationError),
+    CannotDeserializeDocument { doc_id: String, error: SerializationError },
+    CannotLoadBlobContent(NativeError),
+    InvalidKeyPath(KeyPathError),
+    /// A key path goes through `path`, which isn't a collection of the `expected` type.
+    KeyPathTypeMismatch { path: String, expected: ValueType, found: ValueType },
+    /// An index of a key path goes past the start of the array at `path`, or too far past its end.
+    KeyPathIndexOutOfBounds { path: String, index: i64, len: usize },
+    /// A string passed to Couchbase Lite contains a NUL byte, which C strings can't hold.
+    InvalidString(NulError),
+}
+
+impl CouchbaseLiteError {
+    /// Returns the operation that failed, e.g. "save document".
+    pub fn operation(&self) -> &'static str {
+        use CouchbaseLiteError::*;
+        match self {
+            CannotOpenDatabase(_) | DatabaseNotFound(_) | DatabaseLocked(_) | DatabaseCorrupt(_) | WrongEncryptionKey(_) => "open database",
+            CannotDeriveEncryptionKey => "derive encryption key",
+            CannotChangeEncryptionKey(_) => "change encryption key",
+            CannotCloseDatabase(_) => "close database",
+            DatabaseNotOpen => "use database",
+            CannotSaveDocument(_) | CannotSaveEmptyDocument => "save document",
+            Conflict { .. } => "write document",
+            CannotDeleteDocument(_) => "delete document",
+            CannotPurgeDocument(_) => "purge document",
+            DocumentNotFound(_) => "find document",
+            DocumentFromOtherDatabase { .. } => "use document",
+            CannotSetDocumentExpiration(_) => "set document expiration",
+            CannotGetDocumentExpiration(_) => "get document expiration",
+            CannotCreateNewQuery(_) => "create query",
+            CannotFillDocumentFromJson(_) => "fill document from JSON",
+            CannotCreateNewReplicator(_) => "create replicator",
+            CannotExecuteQuery(_) => "execute query",
+            CannotCreateIndex(_) => "create index",
+            ErrorInBatch(_) => "run batch",
+            ErrorInTransaction(_) => "run transaction",
+            CannotDeleteDatabase(_) => "delete database",
+            CannotCopyDatabase(_) => "copy database",
+            CannotPerformMaintenance(_) | CannotReadDatabaseSize(_) => "perform maintenance",
+            CannotSerializeDocument(_) => "serialize document",
+            CannotDeserializeDocument { .. } => "deserialize document",
+            CannotLoadBlobContent(_) => "load blob content",
+            InvalidKeyPath(_) => "parse key path",
+            KeyPathTypeMismatch { .. } | KeyPathIndexOutOfBounds { .. } => "follow key path",
+            InvalidString(_) => "pass string",
+        }
+    }
+
+    /// Returns the error reported by Couchbase Lite, if the error comes from the native library.
+    pub fn native(&self) -> Option<&NativeError> {
+        use CouchbaseLiteError::*;
+        match self {
+            CannotOpenDatabase(error)
+            | DatabaseNotFound(error)
+            | DatabaseLocked(error)
+            | DatabaseCorrupt(error)
+            | WrongEncryptionKey(error)
+            | CannotChangeEncryptionKey(error)
+            | CannotCloseDatabase(error)
+            | CannotSaveDocument(error)
+            | CannotDeleteDocument(error)
+            | CannotPurgeDocument(error)
+            | CannotSetDocumentExpiration(error)
+            | CannotGetDocumentExpiration(error)
+            | CannotCreateNewQuery(error)
+            | CannotFillDocumentFromJson(error)
+            | CannotCreateNewReplicator(error)
+            | CannotExecuteQuery(error)
+            | CannotCreateIndex(error)
+            | ErrorInBatch(error)
+            | ErrorInTransaction(error)
+            | CannotDeleteDatabase(error)
+            | CannotCopyDatabase(error)
+            | CannotPerformMaintenance(error)
+            | CannotLoadBlobContent(error) => Some(error),
+            _ => None,
+        }
+    }
+
+    /// Returns the kind of error, decoded from the native error or implied by the variant.
+    pub fn kind(&self) -> Option<ErrorKind> {
+        match self {
+            CouchbaseLiteError::DatabaseNotOpen => Some(ErrorKind::NotOpen),
+            CouchbaseLiteError::Conflict { .. } => Some(ErrorKind::Conflict),
+            CouchbaseLiteError::DocumentNotFound(_) => Some(ErrorKind::NotFound),
+            CouchbaseLiteError::CannotReadDatabaseSize(_) => Some(ErrorKind::IoError),
+            CouchbaseLiteError::InvalidString(_) => Some(ErrorKind::InvalidParameter),
+            _ => self.native().map(|error| error.kind),
+        }
+    }
+
+    /// Returns the ID of the document the operation failed on, when known.
+    pub fn doc_id(&self) -> Option<&str> {
+        match self {
+            CouchbaseLiteError::Conflict { doc_id }
+            | CouchbaseLiteError::DocumentNotFound(doc_id)
+            | CouchbaseLiteError::DocumentFromOtherDatabase { doc_id }
+            | CouchbaseLiteError::CannotDeserializeDocument { doc_id, .. } => Some(doc_id),
+            _ => self.native().and_then(|error| error.doc_id.as_deref()),
+        }
+    }
+}
+
+impl fmt::Display for CouchbaseLiteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cannot {}", self.operation())?;
+        match self {
+            CouchbaseLiteError::DatabaseNotOpen => write!(f, ": the database is closed"),
+            CouchbaseLiteError::CannotSaveEmptyDocument => write!(f, ": the document is empty"),
+            CouchbaseLiteError::Conflict { doc_id } => write!(f, ": document \"{}\" was changed concurrently", doc_id),
+            CouchbaseLiteError::DocumentNotFound(doc_id) => write!(f, ": no document \"{}\"", doc_id),
+            CouchbaseLiteError::DocumentFromOtherDatabase { doc_id } => write!(f, ": document \"{}\" belongs to another database", doc_id),
+            CouchbaseLiteError::CannotReadDatabaseSize(error) => write!(f, ": cannot read the database size: {}", error),
+            CouchbaseLiteError::CannotSerializeDocument(error) => write!(f, ": {}", error),
+            CouchbaseLiteError::CannotDeserializeDocument { doc_id, error } => write!(f, ": document \"{}\": {}", doc_id, error),
+            CouchbaseLiteError::InvalidKeyPath(error) => write!(f, ": {}", error),
+            CouchbaseLiteError::InvalidString(error) => write!(f, ": {}", error),
+            CouchbaseLiteError::KeyPathTypeMismatch { path, expected, found } => write!(f, ": \"{}\" is a {}, expected a {}", path, found, expected),
+            CouchbaseLiteError::KeyPathIndexOutOfBounds { path, index, len } => {
+                write!(f, ": index {} is out of bounds of \"{}\", an array of length {}", index, path, len)
+            }
+            _ => match self.native() {
+                Some(error) => write!(f, ": {}", error),
+                None => Ok(()),
+            },
+        }
+    }
+}
+
+impl Error for CouchbaseLiteError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CouchbaseLiteError::CannotReadDatabaseSize(error) => Some(error),
+            CouchbaseLiteError::CannotSerializeDocument(error) | CouchbaseLiteError::CannotDeserializeDocument { error, .. } => Some(error),
+            CouchbaseLiteError::InvalidKeyPath(error) => Some(error),
+            CouchbaseLiteError::InvalidString(error) => Some(error),
+            _ => self.native().map(|error| error as &(dyn Error + 'static)),
+        }
+    }
+}
+
+/// Kind of an error reported by Couchbase Lite, decoded from the domain and code of a `CBLError`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The database or object was closed.
+    NotOpen,
+    NotFound,
+    /// The document was changed since it was read.
+    Conflict,
+    InvalidParameter,
+    /// The database is locked by another connection or process.
+    Busy,
+    CorruptData,
+    /// The file isn't a database, or it was opened with the wrong encryption key.
+    NotADatabaseFile,
+    /// Encryption or decryption failed.
+    Crypto,
+    /// The query couldn't be compiled.
+    InvalidQuery,
+    /// The query requires an index that doesn't exist.
+    MissingIndex,
+    BadDocId,
+    /// The database is read-only.
+    NotWriteable,
+    Unsupported,
+    IoError,
+    /// Another error code of the Couchbase Lite domain.
+    Couchbase(i32),
+    /// An `errno` value.
+    Posix(i32),
+    /// A SQLite result code.
+    Sqlite(i32),
+    Fleece(i32),
+    /// A network error, e.g. a DNS failure or a timeout.
+    NetworkError(i32),
+    /// An HTTP status returned by the remote server.
+    HttpStatus(i32),
+    /// A WebSocket close code.
+    WebSocket(i32),
+    Unknown { domain: u32, code: i32 },
+}
+
+impl ErrorKind {
+    fn from_native(domain: ffi::CBLErrorDomain, code: i32) -> Self {
+        // domains: 1 -> kCBLDomain, 2 -> kCBLPOSIXDomain, 3 -> kCBLSQLiteDomain, 4 -> kCBLFleeceDomain,
+        // 5 -> kCBLNetworkDomain, 6 -> kCBLWebSocketDomain
+        match (domain, code) {
+            (1, 6) => ErrorKind::NotOpen,
+            (1, 7) => ErrorKind::NotFound,
+            (1, 8) => ErrorKind::Conflict,
+            (1, 9) => ErrorKind::InvalidParameter,
+            (1, 12) => ErrorKind::IoError,
+            (1, 14) => ErrorKind::NotWriteable,
+            // kCBLErrorCorruptRevisionData, kCBLErrorCorruptData
+            (1, 5) | (1, 15) => ErrorKind::CorruptData,
+            (1, 16) => ErrorKind::Busy,
+            // kCBLErrorUnimplemented, kCBLErrorUnsupported
+            (1, 2) | (1, 19) => ErrorKind::Unsupported,
+            (1, 20) => ErrorKind::NotADatabaseFile,
+            (1, 22) => ErrorKind::Crypto,
+            // kCBLErrorInvalidQuery, kCBLErrorInvalidQueryParam
+            (1, 23) | (1, 25) => ErrorKind::InvalidQuery,
+            (1, 24) => ErrorKind::MissingIndex,
+            (1, 29) => ErrorKind::BadDocId,
+            (1, code) => ErrorKind::Couchbase(code),
+            (2, code) => ErrorKind::Posix(code),
+            (3, code) => ErrorKind::Sqlite(code),
+            (4, code) => ErrorKind::Fleece(code),
+            (5, code) => ErrorKind::NetworkError(code),
+            // WebSocket codes below 1000 are HTTP statuses
+            (6, code) if code < 1000 => ErrorKind::HttpStatus(code),
+            (6, code) => ErrorKind::WebSocket(code),
+            (domain, code) => ErrorKind::Unknown { domain, code },
+        }
+    }
+}
+
+/// An error reported by Couchbase Lite, wrapping `CBLError`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NativeError {
+    pub kind: ErrorKind,
+    pub domain: u32,
+    pub code: i32,
+    /// The message of the native library, from `CBLError_Message`.
+    pub message: String,
+    /// The ID of the document the operation failed on, when known.
+    pub doc_id: Option<String>,
 }
 
+impl NativeError {
+    pub(crate) fn new(error: ffi::CBLError) -> Self {
+        let message = unsafe {
+            let message: *mut c_char = ffi::CBLError_Message(&error);
+            let string = to_string(message);
+            if !message.is_null() {
+                free(message as *mut c_void);
+            }
+            string
+        };
+        NativeError {
+            kind: ErrorKind::from_native(error.domain, error.code),
+            domain: error.domain,
+            code: error.code,
+            message,
+            doc_id: None,
+        }
+    }
+
+    pub(crate) fn for_document<S: Into<String>>(error: ffi::CBLError, doc_id: S) -> Self {
+        NativeError {
+            doc_id: Some(doc_id.into()),
+            ..NativeError::new(error)
+        }
+    }
+}
+
+impl fmt::Display for NativeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(doc_id) = &self.doc_id {
+            write!(f, "document \"{}\": ", doc_id)?;
+        }
+        if self.message.is_empty() {
+            write!(f, "{:?}", self.kind)?;
+        } else {
+            write!(f, "{}", self.message)?;
+        }
+        write!(f, " (domain {}, code {})", self.domain, self.code)
+    }
+}
+
+impl Error for NativeError {}
+
 pub fn init_error() -> ffi::CBLError {
     ffi::CBLError {
         domain: 0,
@@ -23,3 +327,96 @@ pub fn init_error() -> ffi::CBLError {
         internal_info: 0,
     }
 }
+
+/// Classifies an error returned by `CBLDatabase_Open` with `config`.
+pub(crate) fn open_error(error: ffi::CBLError, config: &DatabaseConfig) -> CouchbaseLiteError {
+    let error = NativeError::new(error);
+    match error.kind {
+        // ENOENT
+        ErrorKind::NotFound | ErrorKind::Posix(2) => CouchbaseLiteError::DatabaseNotFound(error),
+        // SQLITE_BUSY, SQLITE_LOCKED
+        ErrorKind::Busy | ErrorKind::Sqlite(5) | ErrorKind::Sqlite(6) => CouchbaseLiteError::DatabaseLocked(error),
+        ErrorKind::Crypto => CouchbaseLiteError::WrongEncryptionKey(error),
+        // A wrong key is reported as kCBLErrorNotADatabaseFile (SQLITE_NOTADB), like an unencrypted file that isn't a database
+        ErrorKind::NotADatabaseFile | ErrorKind::Sqlite(26) if config.is_encrypted() => CouchbaseLiteError::WrongEncryptionKey(error),
+        // SQLITE_CORRUPT, SQLITE_NOTADB
+        ErrorKind::NotADatabaseFile | ErrorKind::CorruptData | ErrorKind::Sqlite(11) | ErrorKind::Sqlite(26) => CouchbaseLiteError::DatabaseCorrupt(error),
+        _ => CouchbaseLiteError::CannotOpenDatabase(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::open_error;
+    use crate::{CouchbaseLiteError, DatabaseConfig, EncryptionKey, ErrorKind, NativeError};
+    use std::error::Error;
+
+    fn native_error(domain: u32, code: i32) -> NativeError {
+        NativeError {
+            kind: ErrorKind::from_native(domain, code),
+            domain,
+            code,
+            message: String::new(),
+            doc_id: None,
+        }
+    }
+
+    #[test]
+    fn decode_kinds() {
+        assert_eq!(ErrorKind::NotFound, native_error(1, 7).kind);
+        assert_eq!(ErrorKind::Conflict, native_error(1, 8).kind);
+        assert_eq!(ErrorKind::Busy, native_error(1, 16).kind);
+        assert_eq!(ErrorKind::InvalidQuery, native_error(1, 23).kind);
+        assert_eq!(ErrorKind::Couchbase(30), native_error(1, 30).kind);
+        assert_eq!(ErrorKind::Posix(2), native_error(2, 2).kind);
+        assert_eq!(ErrorKind::Sqlite(5), native_error(3, 5).kind);
+        assert_eq!(ErrorKind::NetworkError(1), native_error(5, 1).kind);
+        assert_eq!(ErrorKind::HttpStatus(401), native_error(6, 401).kind);
+        assert_eq!(ErrorKind::WebSocket(1008), native_error(6, 1008).kind);
+        assert_eq!(ErrorKind::Unknown { domain: 42, code: 1 }, native_error(42, 1).kind);
+    }
+
+    #[test]
+    fn display() {
+        let mut error = native_error(1, 8);
+        error.message = String::from("Document update conflict");
+        error.doc_id = Some(String::from("foo"));
+        let error = CouchbaseLiteError::CannotSaveDocument(error);
+        assert_eq!("save document", error.operation());
+        assert_eq!(Some(ErrorKind::Conflict), error.kind());
+        assert_eq!(Some("foo"), error.doc_id());
+        assert_eq!(
+            "cannot save document: document \"foo\": Document update conflict (domain 1, code 8)",
+            error.to_string()
+        );
+        assert!(error.source().is_some());
+
+        let error = CouchbaseLiteError::CannotCreateIndex(native_error(1, 23));
+        assert_eq!("cannot create index: InvalidQuery (domain 1, code 23)", error.to_string());
+
+        let error = CouchbaseLiteError::DocumentNotFound(String::from("bar"));
+        assert_eq!("cannot find document: no document \"bar\"", error.to_string());
+        assert_eq!(Some(ErrorKind::NotFound), error.kind());
+        assert!(error.source().is_none());
+    }
+
+    #[test]
+    fn classify_open_errors() {
+        // kCBLDomain, kCBLErrorNotADatabaseFile
+        let not_a_database = ffi::CBLError {
+            domain: 1,
+            code: 20,
+            internal_info: 0,
+        };
+        let config = DatabaseConfig::new("/tmp");
+        match open_error(not_a_database, &config) {
+            CouchbaseLiteError::DatabaseCorrupt(_) => {}
+            other => panic!("expected DatabaseCorrupt, got {:?}", other),
+        }
+        let config = config.encryption_key(EncryptionKey::from_bytes([1; 32]));
+        match open_error(not_a_database, &config) {
+            CouchbaseLiteError::WrongEncryptionKey(_) => {}
+            other => panic!("expected WrongEncryptionKey, got {:?}", other),
+        }
+    }
+}
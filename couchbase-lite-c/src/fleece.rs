@@ -0,0 +1,967 @@
+use crate::{slice_as_bytes, slice_result_to_string, to_slice};
+use ffi;
+
+use std::borrow::Cow;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Deref, Index};
+use std::str;
+
+/// Type of a Fleece value, wrapping `FLValueType`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueType {
+    /// The value of a missing key or index.
+    Undefined,
+    Null,
+    Boolean,
+    Number,
+    String,
+    Data,
+    Array,
+    Dict,
+}
+
+impl ValueType {
+    pub(crate) fn from_native(value_type: ffi::FLValueType) -> Self {
+        match value_type {
+            0 => ValueType::Null,
+            1 => ValueType::Boolean,
+            2 => ValueType::Number,
+            3 => ValueType::String,
+            4 => ValueType::Data,
+            5 => ValueType::Array,
+            6 => ValueType::Dict,
+            // -1 -> kFLUndefined
+            _ => ValueType::Undefined,
+        }
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ValueType::Undefined => "undefined",
+            ValueType::Null => "null",
+            ValueType::Boolean => "boolean",
+            ValueType::Number => "number",
+            ValueType::String => "string",
+            ValueType::Data => "data",
+            ValueType::Array => "array",
+            ValueType::Dict => "dict",
+        })
+    }
+}
+
+/// A Fleece value, wrapping `FLValue`.
+///
+/// Values are only handled by reference: a `&Value` is the `FLValue` pointer itself and borrows
+/// the document, result or collection the value belongs to.
+pub struct Value {
+    _private: [u8; 0],
+    // Mutable values aren't thread-safe
+    _marker: PhantomData<*const ()>,
+}
+
+/// A Fleece array, wrapping `FLArray`.
+pub struct Array {
+    _private: [u8; 0],
+    _marker: PhantomData<*const ()>,
+}
+
+/// A Fleece dictionary, wrapping `FLDict`. Keys are always strings.
+pub struct Dict {
+    _private: [u8; 0],
+    _marker: PhantomData<*const ()>,
+}
+
+// The encoding of the undefined special value, returned for missing keys and indexes.
+// Fleece values are 2-byte aligned.
+#[repr(C, align(2))]
+struct UndefinedValue([u8; 2]);
+
+static UNDEFINED: UndefinedValue = UndefinedValue([0x3C, 0x00]);
+
+impl Value {
+    /// Wraps a native value, returning `None` for a null pointer.
+    ///
+    /// # Safety
+    /// The value must outlive `'a`.
+    pub(crate) unsafe fn from_raw<'a>(value: ffi::FLValue) -> Option<&'a Value> {
+        (value as *const Value).as_ref()
+    }
+
+    pub(crate) fn as_raw(&self) -> ffi::FLValue {
+        self as *const Value as ffi::FLValue
+    }
+
+    /// Returns the undefined value, which stands for a missing key or index.
+    pub fn undefined() -> &'static Value {
+        unsafe { &*(&UNDEFINED as *const UndefinedValue as *const Value) }
+    }
+
+    pub fn value_type(&self) -> ValueType {
+        ValueType::from_native(unsafe { ffi::FLValue_GetType(self.as_raw()) })
+    }
+
+    pub fn is_undefined(&self) -> bool {
+        self.value_type() == ValueType::Undefined
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.value_type() == ValueType::Null
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.value_type() {
+            ValueType::Boolean => Some(unsafe { ffi::FLValue_AsBool(self.as_raw()) }),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of an integer that fits in an `i64`.
+    pub fn as_i64(&self) -> Option<i64> {
+        let value = self.as_raw();
+        if self.value_type() != ValueType::Number || !unsafe { ffi::FLValue_IsInteger(value) } {
+            return None;
+        }
+        if unsafe { ffi::FLValue_IsUnsigned(value) } {
+            let unsigned = unsafe { ffi::FLValue_AsUnsigned(value) };
+            if unsigned <= i64::MAX as u64 {
+                Some(unsigned as i64)
+            } else {
+                None
+            }
+        } else {
+            Some(unsafe { ffi::FLValue_AsInt(value) })
+        }
+    }
+
+    /// Returns the value of a non-negative integer.
+    pub fn as_u64(&self) -> Option<u64> {
+        let value = self.as_raw();
+        unsafe {
+            if self.value_type() == ValueType::Number && ffi::FLValue_IsUnsigned(value) {
+                Some(ffi::FLValue_AsUnsigned(value))
+            } else {
+                self.as_i64().filter(|value| *value >= 0).map(|value| value as u64)
+            }
+        }
+    }
+
+    /// Returns the value of any number, integers included.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self.value_type() {
+            ValueType::Number => Some(unsafe { ffi::FLValue_AsDouble(self.as_raw()) }),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of a string, or `None` if it isn't valid UTF-8 (see `as_string_bytes`).
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_string_bytes().and_then(|bytes| str::from_utf8(bytes).ok())
+    }
+
+    /// Returns the raw bytes of a string.
+    pub fn as_string_bytes(&self) -> Option<&[u8]> {
+        match self.value_type() {
+            ValueType::String => Some(slice_as_bytes(unsafe { ffi::FLValue_AsString(self.as_raw()) })),
+            _ => None,
+        }
+    }
+
+    /// Returns the bytes of a data value.
+    pub fn as_data(&self) -> Option<&[u8]> {
+        match self.value_type() {
+            ValueType::Data => Some(slice_as_bytes(unsafe { ffi::FLValue_AsData(self.as_raw()) })),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Array> {
+        unsafe { Array::from_raw(ffi::FLValue_AsArray(self.as_raw())) }
+    }
+
+    pub fn as_dict(&self) -> Option<&Dict> {
+        unsafe { Dict::from_raw(ffi::FLValue_AsDict(self.as_raw())) }
+    }
+
+    /// Encodes the value as JSON (data values are encoded in base64).
+    pub fn to_json(&self) -> String {
+        slice_result_to_string(unsafe { ffi::FLValue_ToJSON(self.as_raw()) })
+    }
+
+    /// Converts the value to a `serde_json::Value`.
+    ///
+    /// Undefined values become `null`, strings are converted lossily and data values become arrays
+    /// of bytes, the way serde_json serializes a `Vec<u8>`.
+    pub fn to_json_value(&self) -> serde_json::Value {
+        match self.value_type() {
+            ValueType::Undefined | ValueType::Null => serde_json::Value::Null,
+            ValueType::Boolean => serde_json::Value::Bool(self.as_bool().unwrap_or_default()),
+            ValueType::Number => match (self.as_i64(), self.as_u64()) {
+                (Some(value), _) => serde_json::Value::from(value),
+                (None, Some(value)) => serde_json::Value::from(value),
+                _ => serde_json::Number::from_f64(self.as_f64().unwrap_or_default()).map_or(serde_json::Value::Null, serde_json::Value::Number),
+            },
+            ValueType::String => serde_json::Value::String(String::from_utf8_lossy(self.as_string_bytes().unwrap_or_default()).into_owned()),
+            ValueType::Data => serde_json::Value::from(self.as_data().unwrap_or_default().to_vec()),
+            ValueType::Array => self.as_array().map_or(serde_json::Value::Null, Array::to_json_value),
+            ValueType::Dict => self.as_dict().map_or(serde_json::Value::Null, Dict::to_json_value),
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.value_type() {
+            ValueType::Undefined => f.write_str("undefined"),
+            _ => f.write_str(&self.to_json()),
+        }
+    }
+}
+
+impl<'a> From<&'a Value> for serde_json::Value {
+    fn from(value: &'a Value) -> Self {
+        value.to_json_value()
+    }
+}
+
+/// Looks up a key if the value is a dict, returning the undefined value otherwise.
+impl<'a> Index<&'a str> for Value {
+    type Output = Value;
+
+    fn index(&self, key: &'a str) -> &Value {
+        self.as_dict().map_or(Value::undefined(), |dict| &dict[key])
+    }
+}
+
+/// Looks up an index if the value is an array, returning the undefined value otherwise.
+impl Index<usize> for Value {
+    type Output = Value;
+
+    fn index(&self, index: usize) -> &Value {
+        self.as_array().map_or(Value::undefined(), |array| &array[index])
+    }
+}
+
+impl Array {
+    /// Wraps a native array, returning `None` for a null pointer.
+    ///
+    /// # Safety
+    /// The array must outlive `'a`.
+    pub(crate) unsafe fn from_raw<'a>(array: ffi::FLArray) -> Option<&'a Array> {
+        (array as *const Array).as_ref()
+    }
+
+    pub(crate) fn as_raw(&self) -> ffi::FLArray {
+        self as *const Array as ffi::FLArray
+    }
+
+    pub fn as_value(&self) -> &Value {
+        unsafe { &*(self as *const Array as *const Value) }
+    }
+
+    pub fn len(&self) -> usize {
+        unsafe { ffi::FLArray_Count(self.as_raw()) as usize }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        unsafe { ffi::FLArray_IsEmpty(self.as_raw()) }
+    }
+
+    /// Returns the value at `index`, or `None` if it is out of bounds.
+    pub fn get(&self, index: usize) -> Option<&Value> {
+        if index < self.len() {
+            unsafe { Value::from_raw(ffi::FLArray_Get(self.as_raw(), index as u32)) }
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> ArrayIter<'_> {
+        ArrayIter { array: self, index: 0 }
+    }
+
+    pub fn to_json_value(&self) -> serde_json::Value {
+        serde_json::Value::Array(self.iter().map(Value::to_json_value).collect())
+    }
+}
+
+impl fmt::Debug for Array {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// Returns the undefined value for an index out of bounds.
+impl Index<usize> for Array {
+    type Output = Value;
+
+    fn index(&self, index: usize) -> &Value {
+        self.get(index).unwrap_or_else(|| Value::undefined())
+    }
+}
+
+impl<'a> IntoIterator for &'a Array {
+    type Item = &'a Value;
+    type IntoIter = ArrayIter<'a>;
+
+    fn into_iter(self) -> ArrayIter<'a> {
+        self.iter()
+    }
+}
+
+/// Iterator over the values of an `Array`.
+pub struct ArrayIter<'a> {
+    array: &'a Array,
+    index: usize,
+}
+
+impl<'a> Iterator for ArrayIter<'a> {
+    type Item = &'a Value;
+
+    fn next(&mut self) -> Option<&'a Value> {
+        let value = self.array.get(self.index)?;
+        self.index += 1;
+        Some(value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.array.len().saturating_sub(self.index);
+        (remaining, Some(remaining))
+    }
+}
+
+impl Dict {
+    /// Wraps a native dict, returning `None` for a null pointer.
+    ///
+    /// # Safety
+    /// The dict must outlive `'a`.
+    pub(crate) unsafe fn from_raw<'a>(dict: ffi::FLDict) -> Option<&'a Dict> {
+        (dict as *const Dict).as_ref()
+    }
+
+    pub(crate) fn as_raw(&self) -> ffi::FLDict {
+        self as *const Dict as ffi::FLDict
+    }
+
+    pub fn as_value(&self) -> &Value {
+        unsafe { &*(self as *const Dict as *const Value) }
+    }
+
+    pub fn len(&self) -> usize {
+        unsafe { ffi::FLDict_Count(self.as_raw()) as usize }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        unsafe { ffi::FLDict_IsEmpty(self.as_raw()) }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        unsafe { Value::from_raw(ffi::FLDict_Get(self.as_raw(), to_slice(key))) }
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Iterates over the entries of the dict. Keys that aren't valid UTF-8 are converted lossily.
+    pub fn iter(&self) -> DictIter<'_> {
+        let mut iterator = unsafe { std::mem::zeroed() };
+        unsafe { ffi::FLDictIterator_Begin(self.as_raw(), &mut iterator) };
+        DictIter {
+            iterator,
+            remaining: self.len(),
+            _dict: PhantomData,
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = Cow<'_, str>> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub fn to_json_value(&self) -> serde_json::Value {
+        serde_json::Value::Object(self.iter().map(|(key, value)| (key.into_owned(), value.to_json_value())).collect())
+    }
+}
+
+impl fmt::Debug for Dict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+/// Returns the undefined value for a missing key.
+impl<'a> Index<&'a str> for Dict {
+    type Output = Value;
+
+    fn index(&self, key: &'a str) -> &Value {
+        self.get(key).unwrap_or_else(|| Value::undefined())
+    }
+}
+
+impl<'a> IntoIterator for &'a Dict {
+    type Item = (Cow<'a, str>, &'a Value);
+    type IntoIter = DictIter<'a>;
+
+    fn into_iter(self) -> DictIter<'a> {
+        self.iter()
+    }
+}
+
+/// Iterator over the entries of a `Dict`, wrapping `FLDictIterator`.
+pub struct DictIter<'a> {
+    iterator: ffi::FLDictIterator,
+    remaining: usize,
+    _dict: PhantomData<&'a Dict>,
+}
+
+impl<'a> Iterator for DictIter<'a> {
+    type Item = (Cow<'a, str>, &'a Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let entry = unsafe {
+            let key = slice_as_bytes(ffi::FLDictIterator_GetKeyString(&self.iterator));
+            let value = Value::from_raw(ffi::FLDictIterator_GetValue(&self.iterator));
+            ffi::FLDictIterator_Next(&mut self.iterator);
+            value.map(|value| (String::from_utf8_lossy(key), value))
+        };
+        self.remaining -= 1;
+        entry
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a> Drop for DictIter<'a> {
+    fn drop(&mut self) {
+        unsafe { ffi::FLDictIterator_End(&mut self.iterator) };
+    }
+}
+
+/// An owned, mutable Fleece dictionary, wrapping `FLMutableDict`.
+///
+/// The collections nested in it are changed in place through the `MutableDictRef` and
+/// `MutableArrayRef` returned by `get_mutable_dict` and `get_mutable_array`, which borrow it.
+pub struct MutableDict {
+    dict: ffi::FLMutableDict,
+}
+
+impl MutableDict {
+    pub fn new() -> Self {
+        MutableDict {
+            dict: unsafe { ffi::FLMutableDict_New() },
+        }
+    }
+
+    /// Returns the slot of `key`, to be filled with its new value.
+    pub fn set(&mut self, key: &str) -> Slot<'_> {
+        dict_set(self.dict, key)
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        unsafe { ffi::FLMutableDict_Remove(self.dict, to_slice(key)) };
+    }
+
+    pub fn clear(&mut self) {
+        unsafe { ffi::FLMutableDict_RemoveAll(self.dict) };
+    }
+
+    /// Returns the dict stored under `key`, made mutable in place if needed, or `None` if the value isn't a dict.
+    pub fn get_mutable_dict(&mut self, key: &str) -> Option<MutableDictRef<'_>> {
+        dict_get_mutable_dict(self.dict, key)
+    }
+
+    /// Returns the array stored under `key`, made mutable in place if needed, or `None` if the value isn't an array.
+    pub fn get_mutable_array(&mut self, key: &str) -> Option<MutableArrayRef<'_>> {
+        dict_get_mutable_array(self.dict, key)
+    }
+}
+
+impl Default for MutableDict {
+    fn default() -> Self {
+        MutableDict::new()
+    }
+}
+
+impl Deref for MutableDict {
+    type Target = Dict;
+
+    fn deref(&self) -> &Dict {
+        unsafe { &*(self.dict as *const Dict) }
+    }
+}
+
+impl fmt::Debug for MutableDict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl Drop for MutableDict {
+    fn drop(&mut self) {
+        unsafe { ffi::FLValue_Release(self.dict as ffi::FLValue) };
+    }
+}
+
+/// A mutable dictionary nested in a mutable collection or a document, which it borrows mutably.
+///
+/// It shares the storage of its parent, so it can't outlive it:
+///
+/// ```compile_fail
+/// use couchbase_lite_c::MutableDict;
+///
+/// let mut dict = MutableDict::new();
+/// dict.set("address").set_value(MutableDict::new().as_value());
+/// let address = dict.get_mutable_dict("address").unwrap();
+/// drop(dict);
+/// address.len();
+/// ```
+pub struct MutableDictRef<'a> {
+    dict: ffi::FLMutableDict,
+    _parent: PhantomData<&'a mut MutableDict>,
+}
+
+impl<'a> MutableDictRef<'a> {
+    fn new(dict: ffi::FLMutableDict) -> Self {
+        MutableDictRef { dict, _parent: PhantomData }
+    }
+
+    /// Wraps a native mutable dict borrowed for `'a`, returning `None` for a null pointer.
+    ///
+    /// # Safety
+    /// The dict must outlive `'a`, and not be changed by anything else meanwhile.
+    pub(crate) unsafe fn from_raw(dict: ffi::FLMutableDict) -> Option<Self> {
+        if dict.is_null() {
+            None
+        } else {
+            Some(MutableDictRef::new(dict))
+        }
+    }
+
+    /// Returns the slot of `key`, to be filled with its new value.
+    pub fn set(&mut self, key: &str) -> Slot<'_> {
+        dict_set(self.dict, key)
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        unsafe { ffi::FLMutableDict_Remove(self.dict, to_slice(key)) };
+    }
+
+    pub fn clear(&mut self) {
+        unsafe { ffi::FLMutableDict_RemoveAll(self.dict) };
+    }
+
+    /// Same as `MutableDict::get_mutable_dict`.
+    pub fn get_mutable_dict(&mut self, key: &str) -> Option<MutableDictRef<'_>> {
+        dict_get_mutable_dict(self.dict, key)
+    }
+
+    /// Same as `MutableDict::get_mutable_array`.
+    pub fn get_mutable_array(&mut self, key: &str) -> Option<MutableArrayRef<'_>> {
+        dict_get_mutable_array(self.dict, key)
+    }
+}
+
+impl<'a> Deref for MutableDictRef<'a> {
+    type Target = Dict;
+
+    fn deref(&self) -> &Dict {
+        unsafe { &*(self.dict as *const Dict) }
+    }
+}
+
+impl<'a> fmt::Debug for MutableDictRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+fn dict_set<'a>(dict: ffi::FLMutableDict, key: &str) -> Slot<'a> {
+    Slot::new(unsafe { ffi::FLMutableDict_Set(dict, to_slice(key)) })
+}
+
+fn dict_get_mutable_dict<'a>(dict: ffi::FLMutableDict, key: &str) -> Option<MutableDictRef<'a>> {
+    unsafe { MutableDictRef::from_raw(ffi::FLMutableDict_GetMutableDict(dict, to_slice(key))) }
+}
+
+fn dict_get_mutable_array<'a>(dict: ffi::FLMutableDict, key: &str) -> Option<MutableArrayRef<'a>> {
+    unsafe { MutableArrayRef::from_raw(ffi::FLMutableDict_GetMutableArray(dict, to_slice(key))) }
+}
+
+/// An owned, mutable Fleece array, wrapping `FLMutableArray`.
+///
+/// Its nested collections are borrowed the same way as those of a `MutableDict`.
+pub struct MutableArray {
+    array: ffi::FLMutableArray,
+}
+
+impl MutableArray {
+    pub fn new() -> Self {
+        MutableArray {
+            array: unsafe { ffi::FLMutableArray_New() },
+        }
+    }
+
+    /// Returns the slot of a new value appended to the array.
+    pub fn append(&mut self) -> Slot<'_> {
+        Slot::new(unsafe { ffi::FLMutableArray_Append(self.array) })
+    }
+
+    /// Returns the slot of the value at `index`, to be replaced.
+    ///
+    /// # Panics
+    /// Panics if `index` is out of bounds.
+    pub fn set(&mut self, index: usize) -> Slot<'_> {
+        array_set(self.array, index)
+    }
+
+    /// Removes `count` values starting at `index` (values out of bounds are ignored).
+    pub fn remove(&mut self, index: usize, count: usize) {
+        array_remove(self.array, index, count);
+    }
+
+    /// Truncates the array, or extends it with null values.
+    ///
+    /// # Panics
+    /// Panics if `len` doesn't fit in 32 bits, the limit of Fleece arrays.
+    pub fn resize(&mut self, len: usize) {
+        array_resize(self.array, len);
+    }
+
+    /// Returns the dict at `index`, made mutable in place if needed, or `None` if the value isn't a dict.
+    pub fn get_mutable_dict(&mut self, index: usize) -> Option<MutableDictRef<'_>> {
+        array_get_mutable_dict(self.array, index)
+    }
+
+    /// Returns the array at `index`, made mutable in place if needed, or `None` if the value isn't an array.
+    pub fn get_mutable_array(&mut self, index: usize) -> Option<MutableArrayRef<'_>> {
+        array_get_mutable_array(self.array, index)
+    }
+}
+
+impl Default for MutableArray {
+    fn default() -> Self {
+        MutableArray::new()
+    }
+}
+
+impl Deref for MutableArray {
+    type Target = Array;
+
+    fn deref(&self) -> &Array {
+        unsafe { &*(self.array as *const Array) }
+    }
+}
+
+impl fmt::Debug for MutableArray {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl Drop for MutableArray {
+    fn drop(&mut self) {
+        unsafe { ffi::FLValue_Release(self.array as ffi::FLValue) };
+    }
+}
+
+/// A mutable array nested in a mutable collection or a document, which it borrows mutably,
+/// like a `MutableDictRef`.
+pub struct MutableArrayRef<'a> {
+    array: ffi::FLMutableArray,
+    _parent: PhantomData<&'a mut MutableArray>,
+}
+
+impl<'a> MutableArrayRef<'a> {
+    /// Wraps a native mutable array borrowed for `'a`, returning `None` for a null pointer.
+    ///
+    /// # Safety
+    /// The array must outlive `'a`, and not be changed by anything else meanwhile.
+    pub(crate) unsafe fn from_raw(array: ffi::FLMutableArray) -> Option<Self> {
+        if array.is_null() {
+            None
+        } else {
+            Some(MutableArrayRef { array, _parent: PhantomData })
+        }
+    }
+
+    /// Returns the slot of a new value appended to the array.
+    pub fn append(&mut self) -> Slot<'_> {
+        Slot::new(unsafe { ffi::FLMutableArray_Append(self.array) })
+    }
+
+    /// Same as `MutableArray::set`.
+    pub fn set(&mut self, index: usize) -> Slot<'_> {
+        array_set(self.array, index)
+    }
+
+    /// Same as `MutableArray::remove`.
+    pub fn remove(&mut self, index: usize, count: usize) {
+        array_remove(self.array, index, count);
+    }
+
+    /// Same as `MutableArray::resize`.
+    pub fn resize(&mut self, len: usize) {
+        array_resize(self.array, len);
+    }
+
+    /// Same as `MutableArray::get_mutable_dict`.
+    pub fn get_mutable_dict(&mut self, index: usize) -> Option<MutableDictRef<'_>> {
+        array_get_mutable_dict(self.array, index)
+    }
+
+    /// Same as `MutableArray::get_mutable_array`.
+    pub fn get_mutable_array(&mut self, index: usize) -> Option<MutableArrayRef<'_>> {
+        array_get_mutable_array(self.array, index)
+    }
+}
+
+impl<'a> Deref for MutableArrayRef<'a> {
+    type Target = Array;
+
+    fn deref(&self) -> &Array {
+        unsafe { &*(self.array as *const Array) }
+    }
+}
+
+impl<'a> fmt::Debug for MutableArrayRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+fn array_len(array: ffi::FLMutableArray) -> usize {
+    unsafe { ffi::FLArray_Count(array as ffi::FLArray) as usize }
+}
+
+fn array_set<'a>(array: ffi::FLMutableArray, index: usize) -> Slot<'a> {
+    let len = array_len(array);
+    assert!(index < len, "index {} out of bounds of an array of length {}", index, len);
+    Slot::new(unsafe { ffi::FLMutableArray_Set(array, index as u32) })
+}
+
+fn array_remove(array: ffi::FLMutableArray, index: usize, count: usize) {
+    // Fleece throws when the range goes past the end of the array
+    let len = array_len(array);
+    let index = index.min(len);
+    let count = count.min(len - index);
+    if count > 0 {
+        unsafe { ffi::FLMutableArray_Remove(array, index as u32, count as u32) };
+    }
+}
+
+fn array_resize(array: ffi::FLMutableArray, len: usize) {
+    assert!(len <= u32::MAX as usize, "array length {} doesn't fit in 32 bits", len);
+    unsafe { ffi::FLMutableArray_Resize(array, len as u32) };
+}
+
+fn array_get_mutable_dict<'a>(array: ffi::FLMutableArray, index: usize) -> Option<MutableDictRef<'a>> {
+    if index >= array_len(array) {
+        return None;
+    }
+    unsafe { MutableDictRef::from_raw(ffi::FLMutableArray_GetMutableDict(array, index as u32)) }
+}
+
+fn array_get_mutable_array<'a>(array: ffi::FLMutableArray, index: usize) -> Option<MutableArrayRef<'a>> {
+    if index >= array_len(array) {
+        return None;
+    }
+    unsafe { MutableArrayRef::from_raw(ffi::FLMutableArray_GetMutableArray(array, index as u32)) }
+}
+
+/// The location of a value in a mutable collection, wrapping `FLSlot`.
+///
+/// A slot must be filled right away: it borrows its collection mutably until then.
+pub struct Slot<'a> {
+    slot: ffi::FLSlot,
+    _collection: PhantomData<&'a mut ()>,
+}
+
+impl<'a> Slot<'a> {
+    fn new(slot: ffi::FLSlot) -> Self {
+        Slot {
+            slot,
+            _collection: PhantomData,
+        }
+    }
+
+    pub(crate) fn as_raw(&self) -> ffi::FLSlot {
+        self.slot
+    }
+
+    pub fn set_null(self) {
+        unsafe { ffi::FLSlot_SetNull(self.slot) };
+    }
+
+    pub fn set_bool(self, value: bool) {
+        unsafe { ffi::FLSlot_SetBool(self.slot, value) };
+    }
+
+    pub fn set_i64(self, value: i64) {
+        unsafe { ffi::FLSlot_SetInt(self.slot, value) };
+    }
+
+    pub fn set_u64(self, value: u64) {
+        unsafe { ffi::FLSlot_SetUInt(self.slot, value) };
+    }
+
+    pub fn set_f64(self, value: f64) {
+        unsafe { ffi::FLSlot_SetDouble(self.slot, value) };
+    }
+
+    pub fn set_str(self, value: &str) {
+        unsafe { ffi::FLSlot_SetString(self.slot, to_slice(value)) };
+    }
+
+    pub fn set_data(self, value: &[u8]) {
+        let data = ffi::FLSlice {
+            buf: value.as_ptr() as *const _,
+            size: value.len(),
+        };
+        unsafe { ffi::FLSlot_SetData(self.slot, data) };
+    }
+
+    /// Stores any value. Collections are deep-copied, so that the stored value doesn't share
+    /// storage with a collection that may still be changed or freed.
+    pub fn set_value(self, value: &Value) {
+        // 3 -> kFLDeepCopyImmutables
+        let copy = match value.value_type() {
+            ValueType::Dict => unsafe { ffi::FLDict_MutableCopy(ffi::FLValue_AsDict(value.as_raw()), 3) as ffi::FLValue },
+            ValueType::Array => unsafe { ffi::FLArray_MutableCopy(ffi::FLValue_AsArray(value.as_raw()), 3) as ffi::FLValue },
+            _ => {
+                unsafe { ffi::FLSlot_SetValue(self.slot, value.as_raw()) };
+                return;
+            }
+        };
+        unsafe {
+            ffi::FLSlot_SetValue(self.slot, copy);
+            ffi::FLValue_Release(copy);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{MutableArray, MutableDict, Value, ValueType};
+    use serde_json::json;
+
+    fn sample() -> MutableDict {
+        let mut tags = MutableArray::new();
+        tags.append().set_str("agent");
+        tags.append().set_i64(7);
+        let mut address = MutableDict::new();
+        address.set("city").set_str("London");
+
+        let mut dict = MutableDict::new();
+        dict.set("name").set_str("James");
+        dict.set("age").set_u64(42);
+        dict.set("ratio").set_f64(0.5);
+        dict.set("active").set_bool(true);
+        dict.set("nothing").set_null();
+        dict.set("bytes").set_data(&[1, 2]);
+        dict.set("tags").set_value(tags.as_value());
+        dict.set("address").set_value(address.as_value());
+        dict
+    }
+
+    #[test]
+    fn read_values() {
+        let dict = sample();
+        assert_eq!(8, dict.len());
+        assert_eq!(Some("James"), dict["name"].as_str());
+        assert_eq!(Some(42), dict["age"].as_i64());
+        assert_eq!(Some(42.0), dict["age"].as_f64());
+        assert_eq!(None, dict["ratio"].as_i64());
+        assert_eq!(Some(true), dict["active"].as_bool());
+        assert!(dict["nothing"].is_null());
+        assert_eq!(Some(&[1u8, 2][..]), dict["bytes"].as_data());
+        assert_eq!(ValueType::Array, dict["tags"].value_type());
+        assert_eq!(Some(7), dict["tags"][1].as_i64());
+        assert_eq!(Some("London"), dict["address"]["city"].as_str());
+    }
+
+    #[test]
+    fn missing_values_are_undefined() {
+        let dict = sample();
+        assert!(dict["missing"].is_undefined());
+        assert!(dict["tags"][2].is_undefined());
+        assert!(dict["name"]["city"].is_undefined());
+        assert!(dict["missing"]["deeper"][0].is_undefined());
+        assert!(dict.get("missing").is_none());
+        assert_eq!(ValueType::Undefined, Value::undefined().value_type());
+    }
+
+    #[test]
+    fn iterate() {
+        let dict = sample();
+        let keys: Vec<String> = dict.keys().map(|key| key.into_owned()).collect();
+        assert_eq!(vec!["active", "address", "age", "bytes", "name", "nothing", "ratio", "tags"], keys);
+        let tags: Vec<String> = dict["tags"].as_array().unwrap().iter().map(|value| value.to_json()).collect();
+        assert_eq!(vec!["\"agent\"", "7"], tags);
+    }
+
+    #[test]
+    fn convert_to_json() {
+        let dict = sample();
+        assert_eq!(
+            json!({
+                "name": "James",
+                "age": 42,
+                "ratio": 0.5,
+                "active": true,
+                "nothing": null,
+                "bytes": [1, 2],
+                "tags": ["agent", 7],
+                "address": {"city": "London"}
+            }),
+            serde_json::Value::from(dict.as_value())
+        );
+    }
+
+    #[test]
+    fn mutate_nested_collections() {
+        let mut dict = sample();
+        dict.get_mutable_dict("address").unwrap().set("zip").set_str("SW1");
+        let mut tags = dict.get_mutable_array("tags").unwrap();
+        tags.set(0).set_str("spy");
+        tags.remove(1, 1);
+        assert!(dict.get_mutable_dict("tags").is_none());
+        dict.remove("nothing");
+        assert_eq!(Some("SW1"), dict["address"]["zip"].as_str());
+        assert_eq!(json!(["spy"]), dict["tags"].to_json_value());
+        assert!(!dict.contains_key("nothing"));
+        dict.clear();
+        assert!(dict.is_empty());
+    }
+
+    #[test]
+    fn remove_out_of_range() {
+        let mut array = MutableArray::new();
+        array.resize(3);
+        array.remove(2, 5);
+        assert_eq!(2, array.len());
+        array.remove(4, 1);
+        array.remove(usize::MAX, usize::MAX);
+        assert_eq!(2, array.len());
+        array.remove(0, usize::MAX);
+        assert!(array.is_empty());
+    }
+
+    #[test]
+    fn set_value_copies_collections() {
+        let mut address = MutableDict::new();
+        address.set("city").set_str("London");
+        let mut dict = MutableDict::new();
+        dict.set("address").set_value(address.as_value());
+        address.set("city").set_str("Paris");
+        drop(address);
+        dict.get_mutable_dict("address").unwrap().set("zip").set_str("SW1");
+        assert_eq!(json!({"city": "London", "zip": "SW1"}), dict["address"].to_json_value());
+    }
+}
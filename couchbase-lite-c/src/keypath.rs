@@ -0,0 +1,377 @@
+use crate::errors::CouchbaseLiteError;
+use crate::fleece::{Dict, MutableArray, MutableArrayRef, MutableDict, MutableDictRef, Slot, Value, ValueType};
+
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// A step of a key path: a property name or an array index.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PathComponent {
+    Key(String),
+    /// An array index, negative indexes counting from the end of the array, at most `u32::MAX` in magnitude.
+    Index(i64),
+}
+
+/// A path to a nested property, in N1QL syntax, e.g. ``items[3].sku`` or ``address.`zip-code` ``.
+///
+/// A path starts with a property name, followed by `.name` or `[index]` steps. Names that aren't
+/// plain identifiers (letters, digits, `_` and `$`, not starting with a digit or `$`) must be quoted
+/// with backticks, a literal backtick being written as two backticks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyPath {
+    path: String,
+    components: Vec<PathComponent>,
+    // The end offset of each component in `path`
+    ends: Vec<usize>,
+}
+
+impl KeyPath {
+    pub fn parse(path: &str) -> Result<KeyPath, KeyPathError> {
+        Parser {
+            path,
+            position: 0,
+            components: Vec::new(),
+            ends: Vec::new(),
+        }
+        .parse()
+    }
+
+    pub fn components(&self) -> &[PathComponent] {
+        &self.components
+    }
+
+    /// Returns the path up to and including the component at `index`, e.g. `items[3]` for the
+    /// component 1 of `items[3].sku`.
+    pub fn prefix(&self, index: usize) -> &str {
+        &self.path[..self.ends[index]]
+    }
+}
+
+impl FromStr for KeyPath {
+    type Err = KeyPathError;
+
+    fn from_str(path: &str) -> Result<KeyPath, KeyPathError> {
+        KeyPath::parse(path)
+    }
+}
+
+impl fmt::Display for KeyPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.path)
+    }
+}
+
+/// A syntax error in a key path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyPathError {
+    pub path: String,
+    /// The byte offset of the error in `path`.
+    pub position: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for KeyPathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid key path \"{}\" at {}: {}", self.path, self.position, self.message)
+    }
+}
+
+impl Error for KeyPathError {}
+
+struct Parser<'a> {
+    path: &'a str,
+    position: usize,
+    components: Vec<PathComponent>,
+    ends: Vec<usize>,
+}
+
+impl<'a> Parser<'a> {
+    fn parse(mut self) -> Result<KeyPath, KeyPathError> {
+        if self.path.is_empty() {
+            return Err(self.error("empty path"));
+        }
+        if self.peek() == Some('[') {
+            return Err(self.error("a path must start with a property name"));
+        }
+        self.parse_key()?;
+        while let Some(c) = self.peek() {
+            match c {
+                '.' => {
+                    self.position += 1;
+                    self.parse_key()?;
+                }
+                '[' => {
+                    self.position += 1;
+                    self.parse_index()?;
+                }
+                _ => return Err(self.error("expected '.' or '['")),
+            }
+        }
+        Ok(KeyPath {
+            path: self.path.to_string(),
+            components: self.components,
+            ends: self.ends,
+        })
+    }
+
+    fn parse_key(&mut self) -> Result<(), KeyPathError> {
+        let key = match self.peek() {
+            Some('`') => self.parse_quoted_key()?,
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                let start = self.position;
+                while let Some(c) = self.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' || c == '$' {
+                        self.position += 1;
+                    } else {
+                        break;
+                    }
+                }
+                self.path[start..self.position].to_string()
+            }
+            _ => return Err(self.error("expected a property name, quote it with backticks if needed")),
+        };
+        self.push(PathComponent::Key(key));
+        Ok(())
+    }
+
+    fn parse_quoted_key(&mut self) -> Result<String, KeyPathError> {
+        let start = self.position;
+        self.position += 1;
+        let mut key = String::new();
+        loop {
+            match self.path[self.position..].find('`') {
+                Some(offset) => {
+                    key.push_str(&self.path[self.position..self.position + offset]);
+                    self.position += offset + 1;
+                    if self.peek() == Some('`') {
+                        key.push('`');
+                        self.position += 1;
+                    } else {
+                        return Ok(key);
+                    }
+                }
+                None => {
+                    self.position = start;
+                    return Err(self.error("unterminated quoted property name"));
+                }
+            }
+        }
+    }
+
+    fn parse_index(&mut self) -> Result<(), KeyPathError> {
+        let start = self.position;
+        let end = match self.path[start..].find(']') {
+            Some(offset) => start + offset,
+            None => return Err(self.error("unterminated array index")),
+        };
+        let digits = self.path[start..end].strip_prefix('-').unwrap_or(&self.path[start..end]);
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(self.error("expected an integer array index"));
+        }
+        // Fleece arrays hold at most u32::MAX values
+        let index = match self.path[start..end].parse::<i64>() {
+            Ok(index) if index.unsigned_abs() <= u64::from(u32::MAX) => index,
+            _ => return Err(self.error("array index out of range")),
+        };
+        self.position = end + 1;
+        self.push(PathComponent::Index(index));
+        Ok(())
+    }
+
+    fn push(&mut self, component: PathComponent) {
+        self.components.push(component);
+        self.ends.push(self.position);
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.path[self.position..].chars().next()
+    }
+
+    fn error(&self, message: &'static str) -> KeyPathError {
+        KeyPathError {
+            path: self.path.to_string(),
+            position: self.position,
+            message,
+        }
+    }
+}
+
+/// Returns the value at `path`, or the undefined value if a key or index on the way is missing.
+pub(crate) fn get<'a>(root: &'a Dict, path: &KeyPath) -> Result<&'a Value, CouchbaseLiteError> {
+    let mut value = root.as_value();
+    for (i, component) in path.components().iter().enumerate() {
+        let expected = match component {
+            PathComponent::Key(_) => ValueType::Dict,
+            PathComponent::Index(_) => ValueType::Array,
+        };
+        match value.value_type() {
+            ValueType::Undefined | ValueType::Null => return Ok(Value::undefined()),
+            found if found != expected => return Err(mismatch(path, i - 1, expected, found)),
+            _ => {}
+        }
+        value = match component {
+            PathComponent::Key(key) => &value[key.as_str()],
+            PathComponent::Index(index) if *index >= 0 => &value[*index as usize],
+            PathComponent::Index(index) => {
+                let len = value.as_array().map_or(0, |array| array.len());
+                match len.checked_sub(index.unsigned_abs() as usize) {
+                    Some(index) => &value[index],
+                    None => return Ok(Value::undefined()),
+                }
+            }
+        };
+    }
+    Ok(value)
+}
+
+/// Stores `value` at `path`, creating the missing intermediate dicts and arrays.
+///
+/// Null values on the way are replaced with new collections too, and arrays are extended with
+/// null values up to the index of the path, by at most `MAX_ARRAY_PADDING` values.
+pub(crate) fn set(root: MutableDictRef, path: &KeyPath, value: &Value) -> Result<(), CouchbaseLiteError> {
+    set_from(&mut Container::Dict(root), path, 0, value)
+}
+
+// Each container borrows its parent, so the path is walked recursively
+fn set_from(container: &mut Container, path: &KeyPath, i: usize, value: &Value) -> Result<(), CouchbaseLiteError> {
+    let components = path.components();
+    let step = container.step(path, i, &components[i])?;
+    let expected = match components.get(i + 1) {
+        Some(PathComponent::Key(_)) => ValueType::Dict,
+        Some(PathComponent::Index(_)) => ValueType::Array,
+        None => {
+            container.slot(step).set_value(value);
+            return Ok(());
+        }
+    };
+    let found = container.value(step).value_type();
+    if found == ValueType::Undefined || found == ValueType::Null {
+        match expected {
+            ValueType::Dict => container.slot(step).set_value(MutableDict::new().as_value()),
+            _ => container.slot(step).set_value(MutableArray::new().as_value()),
+        }
+    } else if found != expected {
+        return Err(mismatch(path, i, expected, found));
+    }
+    match container.child(step, expected) {
+        Some(mut child) => set_from(&mut child, path, i + 1, value),
+        None => Err(mismatch(path, i, expected, found)),
+    }
+}
+
+fn mismatch(path: &KeyPath, index: usize, expected: ValueType, found: ValueType) -> CouchbaseLiteError {
+    CouchbaseLiteError::KeyPathTypeMismatch {
+        path: path.prefix(index).to_string(),
+        expected,
+        found,
+    }
+}
+
+/// The number of null values an array may be padded with to set a value past its end.
+const MAX_ARRAY_PADDING: usize = 1024;
+
+/// A collection on the way of a key path being written.
+enum Container<'a> {
+    Dict(MutableDictRef<'a>),
+    Array(MutableArrayRef<'a>),
+}
+
+/// A component of a key path, resolved against its container.
+#[derive(Clone, Copy)]
+enum Step<'p> {
+    Key(&'p str),
+    Index(usize),
+}
+
+impl<'a> Container<'a> {
+    fn step<'p>(&mut self, path: &KeyPath, i: usize, component: &'p PathComponent) -> Result<Step<'p>, CouchbaseLiteError> {
+        match (self, component) {
+            (Container::Dict(_), PathComponent::Key(key)) => Ok(Step::Key(key)),
+            (Container::Array(array), PathComponent::Index(index)) if *index >= 0 => {
+                let len = array.len();
+                let index = *index as usize;
+                if index > len + MAX_ARRAY_PADDING {
+                    return Err(CouchbaseLiteError::KeyPathIndexOutOfBounds {
+                        path: path.prefix(i - 1).to_string(),
+                        index: index as i64,
+                        len,
+                    });
+                }
+                if index >= len {
+                    array.resize(index + 1);
+                }
+                Ok(Step::Index(index))
+            }
+            (Container::Array(array), PathComponent::Index(index)) => match array.len().checked_sub(index.unsigned_abs() as usize) {
+                Some(index) => Ok(Step::Index(index)),
+                None => Err(CouchbaseLiteError::KeyPathIndexOutOfBounds {
+                    path: path.prefix(i - 1).to_string(),
+                    index: *index,
+                    len: array.len(),
+                }),
+            },
+            // Containers are created from the type of the next component
+            _ => unreachable!("key path component doesn't match its container"),
+        }
+    }
+
+    fn value(&self, step: Step) -> &Value {
+        match (self, step) {
+            (Container::Dict(dict), Step::Key(key)) => &dict[key],
+            (Container::Array(array), Step::Index(index)) => &array[index],
+            _ => Value::undefined(),
+        }
+    }
+
+    fn slot(&mut self, step: Step) -> Slot<'_> {
+        match (self, step) {
+            (Container::Dict(dict), Step::Key(key)) => dict.set(key),
+            (Container::Array(array), Step::Index(index)) => array.set(index),
+            _ => unreachable!("key path component doesn't match its container"),
+        }
+    }
+
+    fn child(&mut self, step: Step, value_type: ValueType) -> Option<Container<'_>> {
+        match (self, step, value_type) {
+            (Container::Dict(dict), Step::Key(key), ValueType::Dict) => dict.get_mutable_dict(key).map(Container::Dict),
+            (Container::Dict(dict), Step::Key(key), _) => dict.get_mutable_array(key).map(Container::Array),
+            (Container::Array(array), Step::Index(index), ValueType::Dict) => array.get_mutable_dict(index).map(Container::Dict),
+            (Container::Array(array), Step::Index(index), _) => array.get_mutable_array(index).map(Container::Array),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{KeyPath, PathComponent};
+
+    fn key(key: &str) -> PathComponent {
+        PathComponent::Key(key.to_string())
+    }
+
+    #[test]
+    fn parse() {
+        let path = KeyPath::parse("items[3].sku").unwrap();
+        assert_eq!(&[key("items"), PathComponent::Index(3), key("sku")], path.components());
+        assert_eq!("items[3]", path.prefix(1));
+
+        let path: KeyPath = "`first.name`.`back``tick`[-1]._$x1".parse().unwrap();
+        assert_eq!(&[key("first.name"), key("back`tick"), PathComponent::Index(-1), key("_$x1")], path.components());
+        assert_eq!("`first.name`.`back``tick`", path.prefix(1));
+    }
+
+    #[test]
+    fn parse_errors() {
+        for (path, position) in &[("", 0), ("[0]", 0), ("a.", 2), ("a..b", 2), ("1a", 0), ("a[x]", 2), ("a[]", 2), ("a[0", 2), ("a`b`", 1), ("`a", 0), ("a b", 1)] {
+            let error = KeyPath::parse(path).unwrap_err();
+            assert_eq!(*position, error.position, "{}", error);
+        }
+        for path in &["a[4294967296]", "a[-4294967296]", "a[99999999999999999999]"] {
+            assert_eq!("array index out of range", KeyPath::parse(path).unwrap_err().message);
+        }
+        let path = KeyPath::parse("a[4294967295]").unwrap();
+        assert_eq!(PathComponent::Index(4_294_967_295), path.components()[1]);
+    }
+}
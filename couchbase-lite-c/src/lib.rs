@@ -2,39 +2,100 @@ extern crate couchbase_lite_c_sys as ffi;
 extern crate uuid;
 
 use std::ffi::{CStr, CString};
-use std::os::raw::c_char;
-
-use std::mem;
-use std::str;
+use std::os::raw::{c_char, c_void};
+use std::slice;
 
 pub use authenticator::*;
+pub use blob::Blob;
+pub use config::*;
 pub use database::*;
 pub use document::*;
 pub use errors::*;
+pub use fleece::*;
+pub use keypath::*;
+pub use listener::ListenerToken;
+pub use maintenance::*;
 pub use query::*;
 pub use replicator::*;
 pub use resultset::*;
+pub use serialization::SerializationError;
+pub use typed::*;
 
 mod authenticator;
+mod blob;
+mod config;
 mod database;
 mod document;
 mod errors;
+mod fleece;
+mod keypath;
+mod listener;
+mod maintenance;
 mod query;
 mod replicator;
 mod resultset;
+mod rollback;
+mod serialization;
+mod typed;
 
-/// Convert a native string to a Rust string
+/// Convert a native string to a Rust string, replacing invalid UTF-8 with U+FFFD.
 fn to_string(pointer: *const c_char) -> String {
-    let slice = unsafe { CStr::from_ptr(pointer).to_bytes() };
-    str::from_utf8(slice).unwrap().to_string()
-}
-
-/// Convert a Rust string to a native string
-fn to_ptr(string: String) -> *const c_char {
-    let cs = CString::new(string.as_bytes()).unwrap();
-    let ptr = cs.as_ptr();
-    // Tell Rust not to clean up the string while we still have a pointer to it.
-    // Otherwise, we'll get a segfault.
-    mem::forget(cs);
-    ptr
+    String::from_utf8_lossy(to_bytes(pointer)).into_owned()
+}
+
+/// Borrow the bytes of a native string, without its NUL terminator.
+fn to_bytes<'a>(pointer: *const c_char) -> &'a [u8] {
+    if pointer.is_null() {
+        &[]
+    } else {
+        unsafe { CStr::from_ptr(pointer).to_bytes() }
+    }
+}
+
+/// Convert a Rust string to a native string, failing with `InvalidString` if it contains a NUL byte.
+///
+/// The `CString` must outlive every use of its pointer, so bind it to a variable for the
+/// duration of the call: `let id = to_cstring(id)?; ffi::CBLDocument_New(id.as_ptr())`.
+fn to_cstring(string: &str) -> Result<CString, errors::CouchbaseLiteError> {
+    CString::new(string).map_err(errors::CouchbaseLiteError::InvalidString)
+}
+
+/// Borrow a Rust string as a Fleece slice, only valid while `string` is.
+fn to_slice(string: &str) -> ffi::FLSlice {
+    ffi::FLSlice {
+        buf: string.as_ptr() as *const c_void,
+        size: string.len(),
+    }
+}
+
+/// Borrow the bytes of a Fleece slice (empty for a null slice), only valid while the slice is.
+fn slice_as_bytes<'a>(slice: ffi::FLSlice) -> &'a [u8] {
+    if slice.buf.is_null() {
+        &[]
+    } else {
+        unsafe { slice::from_raw_parts(slice.buf as *const u8, slice.size) }
+    }
+}
+
+/// Copy the bytes of a Fleece slice (empty for a null slice).
+fn slice_to_bytes(slice: ffi::FLSlice) -> Vec<u8> {
+    slice_as_bytes(slice).to_vec()
+}
+
+/// Copy the bytes of a Fleece slice returned by the native library, releasing the slice.
+fn slice_result_to_bytes(result: ffi::FLSliceResult) -> Vec<u8> {
+    let bytes = slice_to_bytes(ffi::FLSlice {
+        buf: result.buf,
+        size: result.size,
+    });
+    if !result.buf.is_null() {
+        unsafe { ffi::FLSliceResult_Release(result) };
+    }
+    bytes
+}
+
+/// Convert a Fleece slice returned by the native library to a Rust string, releasing the slice.
+/// Invalid UTF-8 is replaced with U+FFFD.
+fn slice_result_to_string(result: ffi::FLSliceResult) -> String {
+    String::from_utf8_lossy(&slice_result_to_bytes(result)).into_owned()
 }
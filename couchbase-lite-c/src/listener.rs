@@ -0,0 +1,97 @@
+use crate::database::{Database, SharedState};
+use crate::to_string;
+use ffi;
+
+use std::any::Any;
+use std::os::raw::{c_char, c_uint, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::slice;
+use std::sync::Weak;
+
+// The C library may call the same listener from several threads at once
+pub(crate) type DatabaseChangeCallback = Box<dyn Fn(&Database, &[String]) + Send + Sync + 'static>;
+pub(crate) type DocumentChangeCallback = Box<dyn Fn(&Database, &str) + Send + Sync + 'static>;
+pub(crate) type NotificationsReadyCallback = Box<dyn Fn(&Database) + Send + Sync + 'static>;
+
+/// The context given to the C library along with a listener: the callback, and the state of the
+/// database it was registered on, so that the callback gets a handle sharing it.
+///
+/// The state is weakly referenced, so a listener doesn't keep the database open.
+pub(crate) struct ListenerContext<C> {
+    pub(crate) database: Weak<SharedState>,
+    pub(crate) callback: C,
+}
+
+impl<C> ListenerContext<C> {
+    pub(crate) fn as_ptr(&self) -> *mut c_void {
+        self as *const ListenerContext<C> as *mut c_void
+    }
+}
+
+/// Handle of a registered listener, wrapping `CBLListenerToken`.
+///
+/// The listener is removed when the token is dropped, so keep it alive as long as
+/// notifications are needed.
+pub struct ListenerToken {
+    token: *mut ffi::CBLListenerToken,
+    // The callback boxed behind the context pointer given to the C library
+    _callback: Box<dyn Any + Send>,
+}
+
+impl ListenerToken {
+    pub(crate) fn new(token: *mut ffi::CBLListenerToken, callback: Box<dyn Any + Send>) -> Self {
+        ListenerToken { token, _callback: callback }
+    }
+}
+
+impl Drop for ListenerToken {
+    fn drop(&mut self) {
+        // Remove the listener before its callback is freed
+        unsafe { ffi::CBLListener_Remove(self.token) };
+    }
+}
+
+/// Runs a listener callback, making sure a panic doesn't unwind into the C library.
+///
+/// The panic is dropped: the panic hook has already reported it.
+pub(crate) fn catch_panic<F: FnOnce()>(callback: F) {
+    let _ = panic::catch_unwind(AssertUnwindSafe(callback));
+}
+
+pub(crate) unsafe extern "C" fn database_change_listener(
+    context: *mut c_void,
+    _db: *const ffi::CBLDatabase,
+    num_docs: c_uint,
+    doc_ids: *mut *const c_char,
+) {
+    catch_panic(|| {
+        let context = &*(context as *const ListenerContext<DatabaseChangeCallback>);
+        let doc_ids: Vec<String> = if doc_ids.is_null() {
+            Vec::new()
+        } else {
+            slice::from_raw_parts(doc_ids, num_docs as usize).iter().map(|doc_id| to_string(*doc_id)).collect()
+        };
+        if let Some(database) = Database::from_listener(&context.database) {
+            (context.callback)(&database, &doc_ids);
+        }
+    });
+}
+
+pub(crate) unsafe extern "C" fn document_change_listener(context: *mut c_void, _db: *const ffi::CBLDatabase, doc_id: *const c_char) {
+    catch_panic(|| {
+        let context = &*(context as *const ListenerContext<DocumentChangeCallback>);
+        let doc_id = to_string(doc_id);
+        if let Some(database) = Database::from_listener(&context.database) {
+            (context.callback)(&database, &doc_id);
+        }
+    });
+}
+
+pub(crate) unsafe extern "C" fn notifications_ready(context: *mut c_void, _db: *mut ffi::CBLDatabase) {
+    catch_panic(|| {
+        let context = &*(context as *const ListenerContext<NotificationsReadyCallback>);
+        if let Some(database) = Database::from_listener(&context.database) {
+            (context.callback)(&database);
+        }
+    });
+}
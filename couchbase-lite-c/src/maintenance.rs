@@ -0,0 +1,64 @@
+use ffi;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Maintenance operations that can be run on a `Database`, wrapping `CBLMaintenanceType`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaintenanceType {
+    /// Compacts the database file and deletes unused attachments.
+    Compact,
+    /// Rebuilds the indexes (for debugging purposes).
+    Reindex,
+    /// Checks for database corruption, returning an error if found.
+    IntegrityCheck,
+    /// Quickly updates the index statistics used by the query optimizer.
+    Optimize,
+    /// Fully scans all indexes to gather the statistics used by the query optimizer.
+    FullOptimize,
+}
+
+impl MaintenanceType {
+    pub(crate) fn to_native(self) -> ffi::CBLMaintenanceType {
+        match self {
+            MaintenanceType::Compact => 0,
+            MaintenanceType::Reindex => 1,
+            MaintenanceType::IntegrityCheck => 2,
+            MaintenanceType::Optimize => 3,
+            MaintenanceType::FullOptimize => 4,
+        }
+    }
+}
+
+/// Outcome of `Database::perform_maintenance`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MaintenanceReport {
+    pub maintenance_type: MaintenanceType,
+    /// Size in bytes of the `.cblite2` directory before the operation.
+    pub size_before: u64,
+    /// Size in bytes of the `.cblite2` directory after the operation.
+    pub size_after: u64,
+}
+
+impl MaintenanceReport {
+    /// Returns the number of bytes freed by the operation (0 if the database grew).
+    pub fn reclaimed(&self) -> u64 {
+        self.size_before.saturating_sub(self.size_after)
+    }
+}
+
+/// Returns the total size of the files in a database directory.
+pub(crate) fn database_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += database_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
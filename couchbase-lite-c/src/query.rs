@@ -2,6 +2,7 @@ use ffi;
 
 use crate::errors::init_error;
 use crate::errors::CouchbaseLiteError;
+use crate::errors::NativeError;
 use crate::resultset::ResultSet;
 use std::mem;
 
@@ -16,7 +17,7 @@ impl Query {
         if error.code == 0 {
             Ok(ResultSet { rs })
         } else {
-            Err(CouchbaseLiteError::CannotExecuteQuery(error))
+            Err(CouchbaseLiteError::CannotExecuteQuery(NativeError::new(error)))
         }
     }
 }
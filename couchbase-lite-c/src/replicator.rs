@@ -2,22 +2,26 @@
 
 use crate::authenticator::Authenticator;
 use crate::database::Database;
-use crate::to_ptr;
+use crate::to_cstring;
 use ffi;
 
 use crate::errors::init_error;
 use crate::errors::CouchbaseLiteError;
+use crate::errors::NativeError;
 use std::mem;
 
 pub struct Replicator {
     replicator: *mut ffi::CBLReplicator,
+    // Keeps the database open as long as the replicator uses it
+    _database: Database,
 }
 
 impl Replicator {
     pub fn new(database: Database, target_url: String) -> Result<Self, CouchbaseLiteError> {
         let mut error = init_error();
+        let target_url = to_cstring(&target_url)?;
         let replicator = unsafe {
-            let endpoint = ffi::CBLEndpoint_NewWithURL(to_ptr(target_url));
+            let endpoint = ffi::CBLEndpoint_NewWithURL(target_url.as_ptr());
             /*
             CBLReplicatorTypePushAndPull = 0,    ///< Bidirectional; both push and pull
             CBLReplicatorTypePush,               ///< Pushing changes to the target
@@ -44,16 +48,17 @@ impl Replicator {
             ffi::CBLReplicator_New(&config, &mut error)
         };
         if error.code == 0 {
-            Ok(Replicator { replicator })
+            Ok(Replicator { replicator, _database: database })
         } else {
-            Err(CouchbaseLiteError::CannotCreateNewReplicator(error))
+            Err(CouchbaseLiteError::CannotCreateNewReplicator(NativeError::new(error)))
         }
     }
 
     pub fn new_with_auth(database: Database, target_url: String, auth: Authenticator) -> Result<Self, CouchbaseLiteError> {
         let mut error = init_error();
+        let target_url = to_cstring(&target_url)?;
         let replicator = unsafe {
-            let endpoint = ffi::CBLEndpoint_NewWithURL(to_ptr(target_url));
+            let endpoint = ffi::CBLEndpoint_NewWithURL(target_url.as_ptr());
             /*
             CBLReplicatorTypePushAndPull = 0,    ///< Bidirectional; both push and pull
             CBLReplicatorTypePush,               ///< Pushing changes to the target
@@ -80,9 +85,9 @@ impl Replicator {
             ffi::CBLReplicator_New(&config, &mut error)
         };
         if error.code == 0 {
-            Ok(Replicator { replicator })
+            Ok(Replicator { replicator, _database: database })
         } else {
-            Err(CouchbaseLiteError::CannotCreateNewReplicator(error))
+            Err(CouchbaseLiteError::CannotCreateNewReplicator(NativeError::new(error)))
         }
     }
 
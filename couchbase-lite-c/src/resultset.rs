@@ -1,9 +1,7 @@
-use core::slice;
 use ffi;
-use std::ffi::CStr;
-use std::{str, mem};
+use std::mem;
 
-use crate::to_ptr;
+use crate::{to_cstring, Value, ValueType};
 
 pub struct ResultSet {
     pub rs: *mut ffi::CBLResultSet,
@@ -14,25 +12,26 @@ impl ResultSet {
         unsafe { ffi::CBLResultSet_Next(self.rs) }
     }
 
-    // return Doc
+    /// Returns the value of a column as text: dicts are converted to JSON, and invalid UTF-8
+    /// (e.g. binary data) is replaced with U+FFFD. Use `value_bytes` to get the raw bytes.
     pub fn value(&self, key: String) -> String {
-        let slice = unsafe {
-            //let value = ffi::CBLResultSet_ValueAtIndex(self.rs, 1);
-            let value = ffi::CBLResultSet_ValueForKey(self.rs, to_ptr(key));
-            let fl_type = ffi::FLValue_GetType(value);
-            match fl_type {
-                6 => {
-                    // TODO see FLValue_ToJSON5
-                    let fl_slice = ffi::FLValue_ToJSON(value);
-                    CStr::from_bytes_with_nul_unchecked(slice::from_raw_parts(fl_slice.buf as *const u8, fl_slice.size + 1)).to_bytes()
-                }
-                _ => {
-                    let fl_slice = ffi::FLValue_AsString(value);
-                    CStr::from_bytes_with_nul_unchecked(slice::from_raw_parts(fl_slice.buf as *const u8, fl_slice.size + 1)).to_bytes()
-                }
-            }
+        String::from_utf8_lossy(&self.value_bytes(key)).into_owned()
+    }
+
+    /// Returns the raw bytes of a column: the bytes of a string or data value, or the JSON of a dict.
+    pub fn value_bytes(&self, key: String) -> Vec<u8> {
+        // No column name contains a NUL byte
+        let value = match to_cstring(&key) {
+            Ok(key) => unsafe { Value::from_raw(ffi::CBLResultSet_ValueForKey(self.rs, key.as_ptr())) },
+            Err(_) => None,
         };
-        str::from_utf8(slice).unwrap().to_string()
+        let value = value.unwrap_or_else(|| Value::undefined());
+        match value.value_type() {
+            ValueType::Data => value.as_data().unwrap_or_default().to_vec(),
+            // TODO see FLValue_ToJSON5
+            ValueType::Dict => value.to_json().into_bytes(),
+            _ => value.as_string_bytes().unwrap_or_default().to_vec(),
+        }
     }
 }
 
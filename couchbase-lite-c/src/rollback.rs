@@ -0,0 +1,85 @@
+use crate::errors::init_error;
+use ffi;
+
+use std::ffi::{CStr, CString};
+
+/// A document as it was before a write made in a batch, written back if the batch is rolled back.
+///
+/// The 2.x C library can't abort a batch: rolling one back restores the snapshots of the documents
+/// it changed, latest first, before committing it. The restored documents get a new revision.
+pub(crate) struct Snapshot {
+    doc_id: CString,
+    // The current revision when the snapshot was taken, null if there was none
+    document: *const ffi::CBLDocument,
+    expiration: ffi::CBLTimestamp,
+}
+
+impl Snapshot {
+    /// Takes a snapshot of a document of `db`, which must be open.
+    pub(crate) unsafe fn take(db: *mut ffi::CBLDatabase, doc_id: &CStr) -> Self {
+        let document = ffi::CBLDatabase_GetDocument(db, doc_id.as_ptr());
+        let mut error = init_error();
+        let expiration = if document.is_null() {
+            0
+        } else {
+            ffi::CBLDatabase_GetDocumentExpiration(db, doc_id.as_ptr(), &mut error).max(0)
+        };
+        Snapshot {
+            doc_id: doc_id.to_owned(),
+            document,
+            expiration,
+        }
+    }
+
+    /// Makes the snapshot the current revision of the document, purging a document that didn't exist.
+    pub(crate) unsafe fn restore(&self, db: *mut ffi::CBLDatabase) -> Result<(), ffi::CBLError> {
+        let mut error = init_error();
+        let current = ffi::CBLDatabase_GetMutableDocument(db, self.doc_id.as_ptr());
+        let restored = if self.document.is_null() {
+            current.is_null() || ffi::CBLDatabase_PurgeDocumentByID(db, self.doc_id.as_ptr(), &mut error)
+        } else if is_deleted(self.document) {
+            // A tombstone purged in the batch can't be brought back
+            // 0 -> kCBLConcurrencyControlLastWriteWins
+            current.is_null() || is_deleted(current) || ffi::CBLDocument_Delete(current, 0, &mut error)
+        } else {
+            let document = if current.is_null() {
+                ffi::CBLDocument_New(self.doc_id.as_ptr())
+            } else {
+                current
+            };
+            // 3 -> kFLDeepCopyImmutables
+            let properties = ffi::FLDict_MutableCopy(ffi::CBLDocument_Properties(self.document), 3);
+            ffi::CBLDocument_SetProperties(document, properties);
+            ffi::FLValue_Release(properties as ffi::FLValue);
+            let saved = ffi::CBLDatabase_SaveDocument(db, document, 0, &mut error);
+            if document != current {
+                ffi::CBL_Release(document as *mut ffi::CBLRefCounted);
+            }
+            !saved.is_null() && {
+                ffi::CBL_Release(saved as *mut ffi::CBLRefCounted);
+                ffi::CBLDatabase_SetDocumentExpiration(db, self.doc_id.as_ptr(), self.expiration, &mut error)
+            }
+        };
+        if !current.is_null() {
+            ffi::CBL_Release(current as *mut ffi::CBLRefCounted);
+        }
+        if error.code == 0 && restored {
+            Ok(())
+        } else {
+            Err(error)
+        }
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if !self.document.is_null() {
+            unsafe { ffi::CBL_Release(self.document as *mut ffi::CBLRefCounted) };
+        }
+    }
+}
+
+// Same as `Database::get_document`: an empty document is a tombstone
+unsafe fn is_deleted(document: *const ffi::CBLDocument) -> bool {
+    ffi::FLDict_IsEmpty(ffi::CBLDocument_Properties(document))
+}
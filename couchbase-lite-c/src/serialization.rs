@@ -0,0 +1,839 @@
+use crate::{slice_as_bytes, to_slice, MutableArray, ValueType};
+use ffi;
+
+use serde::de::value::StringDeserializer;
+use serde::de::{self, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess, VariantAccess, Visitor};
+use serde::ser::{self, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::str;
+
+/// Error raised while converting a Rust value to or from Fleece.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SerializationError {
+    message: String,
+}
+
+impl SerializationError {
+    fn new<T: fmt::Display>(message: T) -> Self {
+        SerializationError { message: message.to_string() }
+    }
+}
+
+impl fmt::Display for SerializationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for SerializationError {}
+
+impl ser::Error for SerializationError {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        SerializationError::new(message)
+    }
+}
+
+impl de::Error for SerializationError {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        SerializationError::new(message)
+    }
+}
+
+/// Serializes `value` into a new mutable dict, without going through JSON.
+///
+/// The caller owns the returned dict and must release it with `FLValue_Release`.
+pub(crate) fn to_mutable_dict<T: Serialize + ?Sized>(value: &T) -> Result<ffi::FLMutableDict, SerializationError> {
+    unsafe {
+        // Slots only exist inside collections, so the root value goes through a temporary array
+        let root = ffi::FLMutableArray_New();
+        let result = value.serialize(SlotSerializer {
+            slot: ffi::FLMutableArray_Append(root),
+        });
+        let result = result.and_then(|()| {
+            let dict = ffi::FLDict_AsMutable(ffi::FLValue_AsDict(ffi::FLArray_Get(root as ffi::FLArray, 0)));
+            if dict.is_null() {
+                Err(SerializationError::new("document properties must be serialized as a map"))
+            } else {
+                ffi::FLValue_Retain(dict as ffi::FLValue);
+                Ok(dict)
+            }
+        });
+        ffi::FLValue_Release(root as ffi::FLValue);
+        result
+    }
+}
+
+/// Serializes `value` as the single item of a new array, since slots only exist inside collections.
+pub(crate) fn to_array_item<T: Serialize + ?Sized>(value: &T) -> Result<MutableArray, SerializationError> {
+    let mut array = MutableArray::new();
+    value.serialize(SlotSerializer {
+        slot: array.append().as_raw(),
+    })?;
+    Ok(array)
+}
+
+/// Deserializes a Fleece value, without going through JSON.
+pub(crate) fn from_value<T: DeserializeOwned>(value: ffi::FLValue) -> Result<T, SerializationError> {
+    T::deserialize(ValueDeserializer { value })
+}
+
+/// Writes a single value into a slot of a mutable array or dict.
+struct SlotSerializer {
+    slot: ffi::FLSlot,
+}
+
+impl SlotSerializer {
+    /// Creates a dict holding a single `variant` key, as serde_json does for enum variants with data.
+    fn variant_dict(variant: &str) -> (NewCollection, ffi::FLSlot) {
+        unsafe {
+            let dict = ffi::FLMutableDict_New();
+            let slot = ffi::FLMutableDict_Set(dict, to_slice(variant));
+            (NewCollection(dict as ffi::FLValue), slot)
+        }
+    }
+}
+
+impl ser::Serializer for SlotSerializer {
+    type Ok = ();
+    type Error = SerializationError;
+    type SerializeSeq = ArraySerializer;
+    type SerializeTuple = ArraySerializer;
+    type SerializeTupleStruct = ArraySerializer;
+    type SerializeTupleVariant = VariantSerializer<ArraySerializer>;
+    type SerializeMap = DictSerializer;
+    type SerializeStruct = DictSerializer;
+    type SerializeStructVariant = VariantSerializer<DictSerializer>;
+
+    fn serialize_bool(self, value: bool) -> Result<(), SerializationError> {
+        unsafe { ffi::FLSlot_SetBool(self.slot, value) };
+        Ok(())
+    }
+
+    fn serialize_i8(self, value: i8) -> Result<(), SerializationError> {
+        self.serialize_i64(i64::from(value))
+    }
+
+    fn serialize_i16(self, value: i16) -> Result<(), SerializationError> {
+        self.serialize_i64(i64::from(value))
+    }
+
+    fn serialize_i32(self, value: i32) -> Result<(), SerializationError> {
+        self.serialize_i64(i64::from(value))
+    }
+
+    fn serialize_i64(self, value: i64) -> Result<(), SerializationError> {
+        unsafe { ffi::FLSlot_SetInt(self.slot, value) };
+        Ok(())
+    }
+
+    fn serialize_u8(self, value: u8) -> Result<(), SerializationError> {
+        self.serialize_u64(u64::from(value))
+    }
+
+    fn serialize_u16(self, value: u16) -> Result<(), SerializationError> {
+        self.serialize_u64(u64::from(value))
+    }
+
+    fn serialize_u32(self, value: u32) -> Result<(), SerializationError> {
+        self.serialize_u64(u64::from(value))
+    }
+
+    fn serialize_u64(self, value: u64) -> Result<(), SerializationError> {
+        unsafe { ffi::FLSlot_SetUInt(self.slot, value) };
+        Ok(())
+    }
+
+    fn serialize_f32(self, value: f32) -> Result<(), SerializationError> {
+        unsafe { ffi::FLSlot_SetFloat(self.slot, value) };
+        Ok(())
+    }
+
+    fn serialize_f64(self, value: f64) -> Result<(), SerializationError> {
+        unsafe { ffi::FLSlot_SetDouble(self.slot, value) };
+        Ok(())
+    }
+
+    fn serialize_char(self, value: char) -> Result<(), SerializationError> {
+        self.serialize_str(value.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, value: &str) -> Result<(), SerializationError> {
+        unsafe { ffi::FLSlot_SetString(self.slot, to_slice(value)) };
+        Ok(())
+    }
+
+    fn serialize_bytes(self, value: &[u8]) -> Result<(), SerializationError> {
+        let data = ffi::FLSlice {
+            buf: value.as_ptr() as *const _,
+            size: value.len(),
+        };
+        unsafe { ffi::FLSlot_SetData(self.slot, data) };
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), SerializationError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), SerializationError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), SerializationError> {
+        unsafe { ffi::FLSlot_SetNull(self.slot) };
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), SerializationError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<(), SerializationError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<(), SerializationError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), SerializationError> {
+        let (dict, slot) = SlotSerializer::variant_dict(variant);
+        let result = value.serialize(SlotSerializer { slot });
+        store_variant(self.slot, dict, result)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<ArraySerializer, SerializationError> {
+        Ok(ArraySerializer::new(self.slot))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<ArraySerializer, SerializationError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<ArraySerializer, SerializationError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<VariantSerializer<ArraySerializer>, SerializationError> {
+        let (dict, slot) = SlotSerializer::variant_dict(variant);
+        Ok(VariantSerializer {
+            slot: self.slot,
+            dict,
+            inner: ArraySerializer::new(slot),
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<DictSerializer, SerializationError> {
+        Ok(DictSerializer::new(self.slot))
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<DictSerializer, SerializationError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<VariantSerializer<DictSerializer>, SerializationError> {
+        let (dict, slot) = SlotSerializer::variant_dict(variant);
+        Ok(VariantSerializer {
+            slot: self.slot,
+            dict,
+            inner: DictSerializer::new(slot),
+        })
+    }
+}
+
+/// A collection created by the serializer, released when dropped: the slot it is stored into
+/// retains it once complete, and it is simply freed if serialization fails.
+struct NewCollection(ffi::FLValue);
+
+impl Drop for NewCollection {
+    fn drop(&mut self) {
+        unsafe { ffi::FLValue_Release(self.0) };
+    }
+}
+
+/// Fills a new mutable array, stored into the slot once complete.
+struct ArraySerializer {
+    slot: ffi::FLSlot,
+    array: NewCollection,
+}
+
+impl ArraySerializer {
+    fn new(slot: ffi::FLSlot) -> Self {
+        ArraySerializer {
+            slot,
+            array: NewCollection(unsafe { ffi::FLMutableArray_New() } as ffi::FLValue),
+        }
+    }
+
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerializationError> {
+        value.serialize(SlotSerializer {
+            slot: unsafe { ffi::FLMutableArray_Append(self.array.0 as ffi::FLMutableArray) },
+        })
+    }
+
+    fn finish(self) -> Result<(), SerializationError> {
+        unsafe { ffi::FLSlot_SetValue(self.slot, self.array.0) };
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for ArraySerializer {
+    type Ok = ();
+    type Error = SerializationError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerializationError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<(), SerializationError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for ArraySerializer {
+    type Ok = ();
+    type Error = SerializationError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerializationError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<(), SerializationError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for ArraySerializer {
+    type Ok = ();
+    type Error = SerializationError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerializationError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<(), SerializationError> {
+        self.finish()
+    }
+}
+
+/// Fills a new mutable dict, stored into the slot once complete.
+struct DictSerializer {
+    slot: ffi::FLSlot,
+    dict: NewCollection,
+    key: Option<String>,
+}
+
+impl DictSerializer {
+    fn new(slot: ffi::FLSlot) -> Self {
+        DictSerializer {
+            slot,
+            dict: NewCollection(unsafe { ffi::FLMutableDict_New() } as ffi::FLValue),
+            key: None,
+        }
+    }
+
+    fn insert<T: Serialize + ?Sized>(&mut self, key: &str, value: &T) -> Result<(), SerializationError> {
+        value.serialize(SlotSerializer {
+            slot: unsafe { ffi::FLMutableDict_Set(self.dict.0 as ffi::FLMutableDict, to_slice(key)) },
+        })
+    }
+
+    fn finish(self) -> Result<(), SerializationError> {
+        unsafe { ffi::FLSlot_SetValue(self.slot, self.dict.0) };
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for DictSerializer {
+    type Ok = ();
+    type Error = SerializationError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerializationError> {
+        self.key = Some(key.serialize(KeySerializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerializationError> {
+        let key = self.key.take().ok_or_else(|| SerializationError::new("map value serialized before its key"))?;
+        self.insert(&key, value)
+    }
+
+    fn end(self) -> Result<(), SerializationError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for DictSerializer {
+    type Ok = ();
+    type Error = SerializationError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), SerializationError> {
+        self.insert(key, value)
+    }
+
+    fn end(self) -> Result<(), SerializationError> {
+        self.finish()
+    }
+}
+
+/// Serializes the data of an enum variant inside a single-key dict.
+struct VariantSerializer<S> {
+    slot: ffi::FLSlot,
+    dict: NewCollection,
+    inner: S,
+}
+
+/// Stores the dict of a variant into its slot, if the variant's data was serialized.
+fn store_variant(slot: ffi::FLSlot, dict: NewCollection, data: Result<(), SerializationError>) -> Result<(), SerializationError> {
+    if data.is_ok() {
+        unsafe { ffi::FLSlot_SetValue(slot, dict.0) };
+    }
+    data
+}
+
+impl ser::SerializeTupleVariant for VariantSerializer<ArraySerializer> {
+    type Ok = ();
+    type Error = SerializationError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerializationError> {
+        self.inner.push(value)
+    }
+
+    fn end(self) -> Result<(), SerializationError> {
+        store_variant(self.slot, self.dict, self.inner.finish())
+    }
+}
+
+impl ser::SerializeStructVariant for VariantSerializer<DictSerializer> {
+    type Ok = ();
+    type Error = SerializationError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), SerializationError> {
+        self.inner.insert(key, value)
+    }
+
+    fn end(self) -> Result<(), SerializationError> {
+        store_variant(self.slot, self.dict, self.inner.finish())
+    }
+}
+
+/// Turns map keys into strings, as Fleece dict keys are always strings.
+struct KeySerializer;
+
+impl KeySerializer {
+    fn unsupported<T>() -> Result<T, SerializationError> {
+        Err(SerializationError::new("map keys must be strings, chars, integers or booleans"))
+    }
+}
+
+impl ser::Serializer for KeySerializer {
+    type Ok = String;
+    type Error = SerializationError;
+    type SerializeSeq = ser::Impossible<String, SerializationError>;
+    type SerializeTuple = ser::Impossible<String, SerializationError>;
+    type SerializeTupleStruct = ser::Impossible<String, SerializationError>;
+    type SerializeTupleVariant = ser::Impossible<String, SerializationError>;
+    type SerializeMap = ser::Impossible<String, SerializationError>;
+    type SerializeStruct = ser::Impossible<String, SerializationError>;
+    type SerializeStructVariant = ser::Impossible<String, SerializationError>;
+
+    fn serialize_bool(self, value: bool) -> Result<String, SerializationError> {
+        Ok(value.to_string())
+    }
+
+    fn serialize_i8(self, value: i8) -> Result<String, SerializationError> {
+        Ok(value.to_string())
+    }
+
+    fn serialize_i16(self, value: i16) -> Result<String, SerializationError> {
+        Ok(value.to_string())
+    }
+
+    fn serialize_i32(self, value: i32) -> Result<String, SerializationError> {
+        Ok(value.to_string())
+    }
+
+    fn serialize_i64(self, value: i64) -> Result<String, SerializationError> {
+        Ok(value.to_string())
+    }
+
+    fn serialize_u8(self, value: u8) -> Result<String, SerializationError> {
+        Ok(value.to_string())
+    }
+
+    fn serialize_u16(self, value: u16) -> Result<String, SerializationError> {
+        Ok(value.to_string())
+    }
+
+    fn serialize_u32(self, value: u32) -> Result<String, SerializationError> {
+        Ok(value.to_string())
+    }
+
+    fn serialize_u64(self, value: u64) -> Result<String, SerializationError> {
+        Ok(value.to_string())
+    }
+
+    fn serialize_f32(self, _value: f32) -> Result<String, SerializationError> {
+        KeySerializer::unsupported()
+    }
+
+    fn serialize_f64(self, _value: f64) -> Result<String, SerializationError> {
+        KeySerializer::unsupported()
+    }
+
+    fn serialize_char(self, value: char) -> Result<String, SerializationError> {
+        Ok(value.to_string())
+    }
+
+    fn serialize_str(self, value: &str) -> Result<String, SerializationError> {
+        Ok(value.to_string())
+    }
+
+    fn serialize_bytes(self, _value: &[u8]) -> Result<String, SerializationError> {
+        KeySerializer::unsupported()
+    }
+
+    fn serialize_none(self) -> Result<String, SerializationError> {
+        KeySerializer::unsupported()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, _value: &T) -> Result<String, SerializationError> {
+        KeySerializer::unsupported()
+    }
+
+    fn serialize_unit(self) -> Result<String, SerializationError> {
+        KeySerializer::unsupported()
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<String, SerializationError> {
+        KeySerializer::unsupported()
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<String, SerializationError> {
+        Ok(variant.to_string())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<String, SerializationError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<String, SerializationError> {
+        KeySerializer::unsupported()
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, SerializationError> {
+        KeySerializer::unsupported()
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, SerializationError> {
+        KeySerializer::unsupported()
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeTupleStruct, SerializationError> {
+        KeySerializer::unsupported()
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, SerializationError> {
+        KeySerializer::unsupported()
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, SerializationError> {
+        KeySerializer::unsupported()
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct, SerializationError> {
+        KeySerializer::unsupported()
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, SerializationError> {
+        KeySerializer::unsupported()
+    }
+}
+
+/// Reads a Fleece value, borrowed from its document or collection.
+struct ValueDeserializer {
+    value: ffi::FLValue,
+}
+
+fn value_string<'de, V: Visitor<'de>>(bytes: &[u8], visitor: V) -> Result<V::Value, SerializationError> {
+    match str::from_utf8(bytes) {
+        Ok(string) => visitor.visit_str(string),
+        Err(_) => visitor.visit_string(String::from_utf8_lossy(bytes).into_owned()),
+    }
+}
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer {
+    type Error = SerializationError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerializationError> {
+        let value = self.value;
+        unsafe {
+            match ValueType::from_native(ffi::FLValue_GetType(value)) {
+                ValueType::Boolean => visitor.visit_bool(ffi::FLValue_AsBool(value)),
+                ValueType::Number if !ffi::FLValue_IsInteger(value) => visitor.visit_f64(ffi::FLValue_AsDouble(value)),
+                ValueType::Number if ffi::FLValue_IsUnsigned(value) => visitor.visit_u64(ffi::FLValue_AsUnsigned(value)),
+                ValueType::Number => visitor.visit_i64(ffi::FLValue_AsInt(value)),
+                ValueType::String => value_string(slice_as_bytes(ffi::FLValue_AsString(value)), visitor),
+                ValueType::Data => visitor.visit_bytes(slice_as_bytes(ffi::FLValue_AsData(value))),
+                ValueType::Array => visitor.visit_seq(ArrayAccess::new(ffi::FLValue_AsArray(value))),
+                ValueType::Dict => visitor.visit_map(DictAccess::new(ffi::FLValue_AsDict(value))),
+                ValueType::Null | ValueType::Undefined => visitor.visit_unit(),
+            }
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerializationError> {
+        // Undefined for a missing property
+        match ValueType::from_native(unsafe { ffi::FLValue_GetType(self.value) }) {
+            ValueType::Null | ValueType::Undefined => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, SerializationError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerializationError> {
+        let value = self.value;
+        unsafe {
+            match ValueType::from_native(ffi::FLValue_GetType(value)) {
+                // A unit variant
+                ValueType::String => {
+                    let variant = String::from_utf8_lossy(slice_as_bytes(ffi::FLValue_AsString(value))).into_owned();
+                    let variant: StringDeserializer<SerializationError> = variant.into_deserializer();
+                    visitor.visit_enum(variant)
+                }
+                // A variant with data, as a single-key dict
+                ValueType::Dict if ffi::FLDict_Count(ffi::FLValue_AsDict(value)) == 1 => {
+                    let mut entries = DictAccess::new(ffi::FLValue_AsDict(value));
+                    let variant = String::from_utf8_lossy(slice_as_bytes(ffi::FLDictIterator_GetKeyString(&entries.iterator))).into_owned();
+                    let value = ffi::FLDictIterator_GetValue(&entries.iterator);
+                    entries.remaining = 0;
+                    visitor.visit_enum(EnumDeserializer { variant, value })
+                }
+                _ => Err(SerializationError::new("expected an enum variant, as a string or a single-key map")),
+            }
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf
+        unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// Walks the values of a Fleece array.
+struct ArrayAccess {
+    array: ffi::FLArray,
+    index: u32,
+    count: u32,
+}
+
+impl ArrayAccess {
+    fn new(array: ffi::FLArray) -> Self {
+        ArrayAccess {
+            array,
+            index: 0,
+            count: unsafe { ffi::FLArray_Count(array) },
+        }
+    }
+}
+
+impl<'de> SeqAccess<'de> for ArrayAccess {
+    type Error = SerializationError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, SerializationError> {
+        if self.index >= self.count {
+            return Ok(None);
+        }
+        let value = unsafe { ffi::FLArray_Get(self.array, self.index) };
+        self.index += 1;
+        seed.deserialize(ValueDeserializer { value }).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some((self.count - self.index) as usize)
+    }
+}
+
+/// Walks the entries of a Fleece dict.
+struct DictAccess {
+    iterator: ffi::FLDictIterator,
+    remaining: u32,
+}
+
+impl DictAccess {
+    fn new(dict: ffi::FLDict) -> Self {
+        let mut iterator: ffi::FLDictIterator = unsafe { mem::zeroed() };
+        unsafe { ffi::FLDictIterator_Begin(dict, &mut iterator) };
+        DictAccess {
+            iterator,
+            remaining: unsafe { ffi::FLDict_Count(dict) },
+        }
+    }
+}
+
+impl Drop for DictAccess {
+    fn drop(&mut self) {
+        unsafe { ffi::FLDictIterator_End(&mut self.iterator) };
+    }
+}
+
+impl<'de> MapAccess<'de> for DictAccess {
+    type Error = SerializationError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, SerializationError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        let key = unsafe { slice_as_bytes(ffi::FLDictIterator_GetKeyString(&self.iterator)) };
+        seed.deserialize(KeyDeserializer(String::from_utf8_lossy(key).into_owned())).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, SerializationError> {
+        let value = unsafe { ffi::FLDictIterator_GetValue(&self.iterator) };
+        unsafe { ffi::FLDictIterator_Next(&mut self.iterator) };
+        self.remaining -= 1;
+        seed.deserialize(ValueDeserializer { value })
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining as usize)
+    }
+}
+
+/// Reads a dict key, parsing it back when the target type expects a number or a boolean.
+struct KeyDeserializer(String);
+
+macro_rules! deserialize_parsed_key {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerializationError> {
+                match self.0.parse() {
+                    Ok(value) => visitor.$visit(value),
+                    Err(_) => visitor.visit_string(self.0),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for KeyDeserializer {
+    type Error = SerializationError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerializationError> {
+        visitor.visit_string(self.0)
+    }
+
+    deserialize_parsed_key! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, SerializationError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerializationError> {
+        let variant: StringDeserializer<SerializationError> = self.0.into_deserializer();
+        visitor.visit_enum(variant)
+    }
+
+    serde::forward_to_deserialize_any! {
+        f32 f64 char str string bytes byte_buf option unit unit_struct
+        seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// Reads an enum variant stored as a single-key dict.
+struct EnumDeserializer {
+    variant: String,
+    value: ffi::FLValue,
+}
+
+impl<'de> EnumAccess<'de> for EnumDeserializer {
+    type Error = SerializationError;
+    type Variant = ValueDeserializer;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, ValueDeserializer), SerializationError> {
+        let variant: StringDeserializer<SerializationError> = self.variant.into_deserializer();
+        let variant = seed.deserialize(variant)?;
+        Ok((variant, ValueDeserializer { value: self.value }))
+    }
+}
+
+impl<'de> VariantAccess<'de> for ValueDeserializer {
+    type Error = SerializationError;
+
+    fn unit_variant(self) -> Result<(), SerializationError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, SerializationError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, SerializationError> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, SerializationError> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
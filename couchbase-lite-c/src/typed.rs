@@ -0,0 +1,28 @@
+use crate::document::Document;
+
+/// A document together with its properties deserialized into a Rust value, as returned by
+/// `Database::get` and `Database::put`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TypedDocument<T> {
+    pub id: String,
+    /// Sequence number of the revision in the local database.
+    pub sequence: u64,
+    pub revision_id: String,
+    pub value: T,
+}
+
+impl<T> TypedDocument<T> {
+    pub(crate) fn new(document: &Document, value: T) -> Self {
+        TypedDocument {
+            id: document.id(),
+            sequence: document.sequence(),
+            revision_id: document.revision_id().unwrap_or_default(),
+            value,
+        }
+    }
+
+    /// Returns the value, dropping the document metadata.
+    pub fn into_value(self) -> T {
+        self.value
+    }
+}
@@ -4,8 +4,10 @@ extern crate uuid;
 use serde::{Deserialize, Serialize};
 use std::time::Duration;
 
+use couchbase_lite_c::CouchbaseLiteError;
 use couchbase_lite_c::Database;
 use couchbase_lite_c::Replicator;
+use couchbase_lite_c::UpdateAction;
 
 static DATABASE_PATH: &str = "/data/local/tmp";
 static TARGET_URL: &str = "ws://127.0.0.1:4984/mydb";
@@ -63,8 +65,6 @@ fn populate_local_database(db_name: &str) {
             None => {
                 database
                     .in_batch(&|| {
-                        let document = database.create_document(doc_id.clone());
-                        println!("Document ID: {:?}", document.id());
                         let person = Person {
                             first_name: "Scott".to_string(),
                             last_name: "Tiger".to_string(),
@@ -73,11 +73,8 @@ fn populate_local_database(db_name: &str) {
                             prop3: None,
                             prop4: None,
                         };
-                        document.fill(serde_json::to_string_pretty(&person).unwrap()).unwrap();
-
-                        //document.set_value("first_name".to_string(), "Scott".to_string());
-                        //document.set_value("last_name".to_string(), "Tiger".to_string());
-                        database.save_document(document).unwrap();
+                        let saved = database.put(&doc_id, &person).unwrap();
+                        println!("Document ID: {:?} - revision: {:?}", saved.id, saved.revision_id);
                     })
                     .unwrap();
             }
@@ -89,29 +86,31 @@ fn populate_local_database(db_name: &str) {
 fn update_local_database(db_name: &str) {
     println!("\n --- \n");
     let database = Database::open(DATABASE_PATH.to_string(), db_name).unwrap();
-    let doc_id = String::from("foo");
-    database.in_batch(&|| {
-        if let Some(doc) = database.get_document(doc_id.clone()) {
-            println!("Doc already exits: {:?}", doc.jsonify());
-            let json = doc.jsonify();
-            let mut data: Person = serde_json::from_str(json.as_str()).unwrap();
-            if data.prop1.is_none() {
-                data.prop1 = Some(format!("{}_val1", db_name));
-            } else if data.prop2.is_none() {
-                data.prop2 = Some(format!("{}_val2", db_name));
-            } else if data.prop3.is_none() {
-                data.prop3 = Some(format!("{}_val3", db_name));
-            } else if data.prop4.is_none() {
-                data.prop4 = Some(format!("{}_val4", db_name));
-            }
-            //let uuid = Uuid::new_v4().to_string();
-            //let property_name = format!("new_property_{}", uuid);
-            //doc.set_value("Bob".to_string(), property_name);
-            doc.fill(serde_json::to_string_pretty(&data).unwrap()).unwrap();
-            println!("Modify existing doc: {:?}", doc.jsonify());
-            database.save_document(doc).unwrap();
+    let doc_id = "foo";
+    let updated = database.update_document(doc_id, |doc| {
+        println!("Doc already exits: {:?}", doc.jsonify());
+        let json = doc.jsonify();
+        let mut data: Person = serde_json::from_str(json.as_str()).unwrap();
+        if data.prop1.is_none() {
+            data.prop1 = Some(format!("{}_val1", db_name));
+        } else if data.prop2.is_none() {
+            data.prop2 = Some(format!("{}_val2", db_name));
+        } else if data.prop3.is_none() {
+            data.prop3 = Some(format!("{}_val3", db_name));
+        } else if data.prop4.is_none() {
+            data.prop4 = Some(format!("{}_val4", db_name));
+        } else {
+            return Ok(UpdateAction::Cancel);
         }
-    }).unwrap();
+        doc.fill(serde_json::to_string_pretty(&data).unwrap())?;
+        Ok(UpdateAction::Save)
+    });
+    match updated {
+        Ok(Some(doc)) => println!("Modify existing doc: {:?}", doc.jsonify()),
+        Ok(None) => println!("Doc left unchanged"),
+        Err(CouchbaseLiteError::DocumentNotFound(_)) => println!("No doc to update"),
+        Err(e) => panic!("Cannot update doc: {:?}", e),
+    }
     database.close().unwrap();
 }
 